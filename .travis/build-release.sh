@@ -82,10 +82,13 @@ RUSTC_DATE=$(rustc_info "commit-date")
 mapfile -t FEATURES < <( .travis/get-features Cargo.toml )
 for flist in "${FEATURES[@]}"; do
     echo "Building with features $flist"
-    eval "cargo build --release --target $TARGET_HOST --verbose --features $flist" || exit 1
+    eval "cargo build --release --target $TARGET_HOST --verbose --no-default-features --features $flist" || exit 1
     echo "Copying binary to release folder"
     rdir=$(eval "echo release/$ARCH/$(echo $flist | tr ' ' +)")
     mkdir -p "$rdir"
     cp "target/$TARGET_HOST/release/perspektiv" "$rdir"
+    if [ -e "target/$TARGET_HOST/release/perspektiv-ctl" ]; then
+        cp "target/$TARGET_HOST/release/perspektiv-ctl" "$rdir"
+    fi
     echo "Done"
 done
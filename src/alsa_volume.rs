@@ -18,99 +18,676 @@
 
 extern crate alsa;
 
-use std::mem;
+use std::{
+    collections::VecDeque,
+    ffi::OsStr,
+    fmt, io, mem,
+    os::unix::io::{AsRawFd, RawFd},
+    path::Path,
+};
 
+use self::alsa::ctl::ElemIface;
 use self::alsa::mixer::{Mixer, Selem, SelemChannelId, SelemId};
 use self::alsa::poll::*;
+use self::alsa::HCtl;
+use libc;
 use libc::pollfd;
 
+use icons::Icon;
+use inotify;
+use inotify::Inotify;
+use reactor::Reactive;
 use subscribable;
-use subscribable::{PollFn, Subscribable};
 use ui;
 
 const SND_CTL_TLV_DB_GAIN_MUTE: i64 = -9_999_999;
 const MAX_LINEAR_DB_SCALE: i64 = 24;
 
+const DEV: &str = "/dev";
+const DEV_SND: &str = "/dev/snd";
+
+/// Configuration section `[modules.alsa_volume]`
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Only watch this card. If unset, all cards are watched.
+    pub card: Option<CardSelector>,
+    /// Names of the mixer controls whose volume is reported, in order of
+    /// preference. Each card uses the first control that it has.
+    pub controls: Vec<String>,
+    /// Index of the mixer control, for cards with several controls of the
+    /// same name.
+    pub control_index: u32,
+    /// Like `controls`, but for the microphone. Leave empty to not report
+    /// microphone volume and mute.
+    pub capture_controls: Vec<String>,
+    pub capture_control_index: u32,
+    /// Names of the jack detection controls (see `amixer -c0 controls`) for
+    /// which plugging and unplugging is reported.
+    pub jacks: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            card: None,
+            controls: vec![
+                "Master".to_string(),
+                "PCM".to_string(),
+                "Speaker".to_string(),
+                "Headphone".to_string(),
+            ],
+            control_index: 0,
+            capture_controls: vec!["Capture".to_string()],
+            capture_control_index: 0,
+            jacks: vec![
+                "Headphone Jack".to_string(),
+                "Front Headphone Jack".to_string(),
+                "Mic Jack".to_string(),
+                "Front Mic Jack".to_string(),
+                "Headset Mic Jack".to_string(),
+                "Line Out Jack".to_string(),
+            ],
+        }
+    }
+}
+
+/// Selects a sound card by its index (`0`), hw id (`"hw:0"` or `"hw:PCH"`) or
+/// name (`"HDA Intel PCH"`).
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum CardSelector {
+    Index(i32),
+    Name(String),
+}
+
+impl CardSelector {
+    fn matches(&self, card: &alsa::Card) -> bool {
+        let id = alsa::Ctl::from_card(card, false)
+            .and_then(|ctl| ctl.card_info())
+            .and_then(|info| info.get_id().map(|id| id.to_string()))
+            .unwrap_or_default();
+        let name = card.get_name().unwrap_or_default();
+        let longname = card.get_longname().unwrap_or_default();
+        self.selects(card.get_index(), &id, &name, &longname)
+    }
+
+    /// Whether this selects the card with the given index, hw id, name and
+    /// long name. Those that cannot be read are empty.
+    fn selects(&self, index: i32, card_id: &str, card_name: &str, card_longname: &str) -> bool {
+        let name = match *self {
+            CardSelector::Index(selected) => return selected == index,
+            CardSelector::Name(ref name) => name,
+        };
+
+        let id = if name.starts_with("hw:") {
+            &name[3..]
+        } else {
+            &name[..]
+        };
+        if let Ok(selected) = id.parse::<i32>() {
+            return selected == index;
+        }
+
+        !id.is_empty() && (card_id == id || card_name == name || card_longname == name)
+    }
+}
+
+impl fmt::Display for CardSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CardSelector::Index(index) => write!(f, "{}", index),
+            CardSelector::Name(ref name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Whether a mixer control is used for playback (speakers) or capture
+/// (microphone).
+#[derive(Clone, Copy, PartialEq)]
+enum Direction {
+    Playback,
+    Capture,
+}
+
+impl Direction {
+    fn icon(self, volume: f64) -> Icon {
+        match self {
+            Direction::Playback => Icon::volume(volume),
+            Direction::Capture => Icon::microphone(volume),
+        }
+    }
+
+    /// Icon and label of the mute popup
+    fn muted(self) -> (Icon, &'static str) {
+        match self {
+            Direction::Playback => (Icon::VolumeMuted, "Muted"),
+            Direction::Capture => (Icon::MicrophoneMuted, "Microphone muted"),
+        }
+    }
+}
+
+/// A mixer control of a card whose volume is reported.
+struct Watched {
+    control: String,
+    index: u32,
+    direction: Direction,
+    /// Last reported volume, or -1.0 if muted
+    volume: f64,
+}
+
+impl Watched {
+    /// Watch the first of `controls` that we can get the volume of on card
+    /// `hwid`.
+    fn find(hwid: &str, controls: &[String], index: u32, direction: Direction) -> Option<Self> {
+        controls
+            .iter()
+            .filter_map(|control| {
+                let mut watched = Watched {
+                    control: control.clone(),
+                    index,
+                    direction,
+                    volume: -2.0,
+                };
+                // This also reads the current volume, so that it is not
+                // reported as a change on the next event.
+                match watched.update(hwid) {
+                    Ok(_) => Some(watched),
+                    Err(_) => None,
+                }
+            }).next()
+    }
+
+    /// Read the volume of the control, returning a message if it changed.
+    fn update(&mut self, hwid: &str) -> alsa::Result<Option<ui::Msg>> {
+        let control = open_control(hwid, &self.control, self.index, self.direction)?;
+        let muted = control.get_mute()?;
+        let volume = control.get_volume()?;
+
+        if muted && self.volume != -1.0 {
+            self.volume = -1.0;
+            let (icon, label) = self.direction.muted();
+            Ok(Some(ui::ShowBool(icon.into(), label.into())))
+        } else if !muted && volume != self.volume {
+            self.volume = volume;
+            Ok(Some(ui::ShowPercent(self.direction.icon(volume).into(), volume)))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// What is plugged into a jack, guessed from the name of its control.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum JackKind {
+    Headphone,
+    Microphone,
+    LineOut,
+    Other,
+}
+
+impl JackKind {
+    fn from_name(name: &str) -> Self {
+        if name.contains("Headphone") {
+            JackKind::Headphone
+        } else if name.contains("Mic") {
+            JackKind::Microphone
+        } else if name.contains("Line Out") {
+            JackKind::LineOut
+        } else {
+            JackKind::Other
+        }
+    }
+
+    /// Icon and label of the popup when a plug is inserted or removed
+    fn popup(self, plugged: bool) -> (Icon, &'static str) {
+        match (self, plugged) {
+            (JackKind::Headphone, true) => (Icon::Headphones, "Headphones connected"),
+            (JackKind::Headphone, false) => (Icon::Headphones, "Headphones disconnected"),
+            (JackKind::Microphone, true) => (Icon::Microphone, "Microphone connected"),
+            (JackKind::Microphone, false) => (Icon::MicrophoneMuted, "Microphone disconnected"),
+            (JackKind::LineOut, true) => (Icon::LineOut, "Line out connected"),
+            (JackKind::LineOut, false) => (Icon::LineOut, "Line out disconnected"),
+            (JackKind::Other, true) => (Icon::Plug, "Plugged in"),
+            (JackKind::Other, false) => (Icon::Plug, "Unplugged"),
+        }
+    }
+}
+
+/// A jack detection control of a card.
+struct Jack {
+    name: String,
+    kind: JackKind,
+    plugged: bool,
+}
+
+/// Read the state of those jacks of a card whose control is named in `names`.
+fn read_jacks(hctl: &HCtl, names: &[String]) -> alsa::Result<Vec<(String, bool)>> {
+    let mut jacks = Vec::new();
+    for elem in hctl.elem_iter() {
+        let id = elem.get_id()?;
+        if id.get_interface() != ElemIface::Card {
+            continue;
+        }
+        let name = match id.get_name() {
+            Ok(name) if names.iter().any(|n| n == name) => name.to_string(),
+            _ => continue,
+        };
+        let plugged = elem.read()?.get_boolean(0).unwrap_or(false);
+        jacks.push((name, plugged));
+    }
+    Ok(jacks)
+}
+
 struct Card {
+    index: i32,
     name: String,
     ctl: alsa::Ctl,
     hwid: String,
-    fd_i: usize,
-    fd_n: usize,
-    volume: f64,
+    playback: Option<Watched>,
+    capture: Option<Watched>,
+    /// Used to read the jack detection controls
+    hctl: HCtl,
+    jacks: Vec<Jack>,
+    /// Poll descriptors of `ctl`
+    fds: Vec<pollfd>,
 }
 
 impl Card {
-    fn new(alsa_card: alsa::Card, poll_fds: &mut Vec<pollfd>) -> alsa::Result<Self> {
+    fn new(alsa_card: alsa::Card, config: &Config) -> alsa::Result<Self> {
+        let index = alsa_card.get_index();
         let name = alsa_card.get_name().unwrap_or("<unknown name>".to_string());
         let ctl = alsa::Ctl::from_card(&alsa_card, false)?;
-        let hwid = format!("hw:{}", alsa_card.get_index());
-
-        let mut fds = PollDescriptors::get(&ctl)?;
-
-        // fd_i is the index where pollfds for this card start. fd_n is the
-        // number of pollfds for this card. This should really only ever be
-        // one, so this whole process could be simplified a lot. The current
-        // behaviour is somewhat unsafe if and only if elements from poll_fd
-        // are ever deleted.
-        // This hacky behaviour is used to track which pollfds describe
-        // which card such that card.ctl.revents() won't fail on invalid
-        // pollfds, while also storing all pollfds in an array for
-        // libc::poll to be happy.
+        let hwid = format!("hw:{}", index);
+
+        // Use the first of the configured controls that we can get the volume
+        // of, else yield error.
+        let playback = Watched::find(
+            &hwid,
+            &config.controls,
+            config.control_index,
+            Direction::Playback,
+        );
+        let capture = Watched::find(
+            &hwid,
+            &config.capture_controls,
+            config.capture_control_index,
+            Direction::Capture,
+        );
+
+        let hctl = HCtl::new(&hwid, false)?;
+        hctl.load()?;
+        let jacks: Vec<Jack> = read_jacks(&hctl, &config.jacks)?
+            .into_iter()
+            .map(|(name, plugged)| Jack {
+                kind: JackKind::from_name(&name),
+                name,
+                plugged,
+            }).collect();
+
+        if playback.is_none() && capture.is_none() && jacks.is_empty() {
+            return Err(alsa::Error::new("find_selem", -1));
+        }
+
+        // it works, subscribe and return
+        ctl.subscribe_events(true)?;
+        let fds = PollDescriptors::get(&ctl)?;
         let card = Card {
+            index,
             name,
             ctl,
             hwid,
-            fd_i: poll_fds.len(),
-            fd_n: fds.len(),
-            volume: -2.0,
+            playback,
+            capture,
+            hctl,
+            jacks,
+            fds,
         };
 
-        // verify that we can get volume, else yield error.
-        card.get_master()?.get_volume()?;
-        // it works, subscribe and return
-        card.ctl.subscribe_events(true)?;
-        poll_fds.append(&mut fds);
+        for watched in card.playback.iter().chain(card.capture.iter()) {
+            info!(
+                "Watching control `{}` of sound card {} ({})",
+                watched.control, card.name, card.hwid
+            );
+        }
+        for jack in &card.jacks {
+            info!(
+                "Watching jack `{}` of sound card {} ({})",
+                jack.name, card.name, card.hwid
+            );
+        }
         Ok(card)
     }
 
-    // FIXME: Need to get a new mixer every time the volume changes for some
-    // reason. This is a bit awkward and seems unnecessary.
-    fn get_master<'a>(&self) -> alsa::Result<Master<'a>> {
-        let mixer = Mixer::new(&self.hwid, false)?;
+    /// Re-read the jacks, returning a message for each one that was plugged
+    /// or unplugged.
+    fn update_jacks(&mut self) -> alsa::Result<Vec<ui::Msg>> {
+        if self.jacks.is_empty() {
+            return Ok(Vec::new());
+        }
 
-        unsafe {
-            // Transmute because the lifetime of selem is not just the
-            // lifetime of the reference to the mixer, but that of the mixer
-            // itself, which is 'a. This is "safe" since Mixer may be moved
-            // in rust code without affecting the underlying FFI code (it's
-            // effectively a box).
-            let selem = mem::transmute::<Selem, Selem<'a>>(match mixer
-                .find_selem(&SelemId::new("Master", 0))
-            {
-                Some(selem) => Ok(selem),
-                None => Err(alsa::Error::new("find_selem", -1)),
-            }?);
+        let mut messages = Vec::new();
+        let names: Vec<String> = self.jacks.iter().map(|jack| jack.name.clone()).collect();
+        for (name, plugged) in read_jacks(&self.hctl, &names)? {
+            if let Some(jack) = self.jacks.iter_mut().find(|jack| jack.name == name) {
+                if jack.plugged != plugged {
+                    jack.plugged = plugged;
+                    let (icon, label) = jack.kind.popup(plugged);
+                    messages.push(ui::ShowBool(icon.into(), label.into()));
+                }
+            }
+        }
+        Ok(messages)
+    }
+}
 
-            Ok(Master(selem, mixer))
+/// Parse the card index from the name of a control device, `controlC<index>`.
+fn control_device_index(file_name: &OsStr) -> Option<i32> {
+    let file_name = file_name.to_str()?;
+    if !file_name.starts_with("controlC") {
+        return None;
+    }
+    file_name["controlC".len()..].parse().ok()
+}
+
+/// The cards being watched. Cards are added and removed as their control
+/// devices appear in and disappear from /dev/snd.
+struct Cards {
+    config: Config,
+    inotify: Inotify,
+    /// Watch descriptor of /dev/snd, if it exists yet
+    snd_watch: Option<i32>,
+    cards: Vec<Card>,
+    /// The inotify descriptor followed by the descriptors of each card, in
+    /// the order of `cards`. Rebuilt whenever `cards` changes.
+    poll_fds: Vec<pollfd>,
+    /// Messages of the controls that changed, until they are handed out
+    pending: VecDeque<ui::Msg>,
+}
+
+impl Cards {
+    fn new(config: Config) -> Result<Self, String> {
+        let inotify = Inotify::new().map_err(|e| format!("Cannot initialise inotify: {}", e))?;
+        // /dev/snd only exists once the first sound card has been registered,
+        // so also watch /dev for it being created.
+        inotify
+            .add_watch(Path::new(DEV), inotify::IN_CREATE)
+            .map_err(|e| format!("Cannot watch {}: {}", DEV, e))?;
+
+        let mut cards = Cards {
+            config,
+            inotify,
+            snd_watch: None,
+            cards: Vec::new(),
+            poll_fds: Vec::new(),
+            pending: VecDeque::new(),
+        };
+        cards.rebuild_poll_fds();
+        cards.watch_snd();
+        cards.scan();
+
+        if cards.cards.is_empty() {
+            info!(
+                "Found no sound cards with a `{}` control{} yet, waiting for one to be added",
+                cards
+                    .config
+                    .controls
+                    .iter()
+                    .chain(cards.config.capture_controls.iter())
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join("`, `"),
+                match cards.config.card {
+                    Some(ref card) => format!(" matching `{}`", card),
+                    None => String::new(),
+                }
+            );
+        }
+        Ok(cards)
+    }
+
+    /// Start watching /dev/snd if it exists and is not yet watched.
+    fn watch_snd(&mut self) {
+        if self.snd_watch.is_some() {
+            return;
+        }
+        self.snd_watch = self
+            .inotify
+            .add_watch(
+                Path::new(DEV_SND),
+                inotify::IN_CREATE | inotify::IN_ATTRIB | inotify::IN_DELETE,
+            ).ok();
+    }
+
+    /// Add all present cards that are not watched yet.
+    fn scan(&mut self) {
+        let indices: Vec<i32> = alsa::card::Iter::new()
+            .filter_map(|card| card.ok())
+            .map(|card| card.get_index())
+            .collect();
+        for index in indices {
+            self.add(index);
+        }
+    }
+
+    /// Add the card with the given index if it is not watched yet, matches the
+    /// configured card and has any of the configured controls.
+    fn add(&mut self, index: i32) {
+        if self.cards.iter().any(|card| card.index == index) {
+            return;
+        }
+        let alsa_card = alsa::Card::new(index);
+        if let Some(ref selector) = self.config.card {
+            if !selector.matches(&alsa_card) {
+                return;
+            }
+        }
+        // This fails if the card is not fully set up yet, e.g. if udev has not
+        // yet adjusted the permissions of its control device. It is retried
+        // when the attributes of the device change.
+        match Card::new(alsa_card, &self.config) {
+            Ok(card) => {
+                self.cards.push(card);
+                self.rebuild_poll_fds();
+            }
+            Err(e) => debug!("Not watching sound card hw:{}: {}", index, e),
+        }
+    }
+
+    fn remove(&mut self, index: i32) {
+        if let Some(i) = self.cards.iter().position(|card| card.index == index) {
+            let card = self.cards.remove(i);
+            info!("Sound card {} ({}) was removed", card.name, card.hwid);
+            self.rebuild_poll_fds();
+        }
+    }
+
+    fn rebuild_poll_fds(&mut self) {
+        self.poll_fds.clear();
+        self.poll_fds.push(pollfd {
+            fd: self.inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        for card in &self.cards {
+            self.poll_fds.extend_from_slice(&card.fds);
+        }
+    }
+
+    /// Handle pending inotify events, adding and removing cards.
+    fn hotplug(&mut self) -> Result<(), subscribable::Error> {
+        let events = self.inotify.read_events().map_err(|e| {
+            subscribable::Error::new(format!("Cannot read inotify events: {}", e), true)
+        })?;
+
+        for event in events {
+            let name = match event.name {
+                Some(ref name) => name,
+                None => continue,
+            };
+            if Some(event.wd) != self.snd_watch {
+                // An event in /dev
+                if name == "snd" {
+                    self.watch_snd();
+                    self.scan();
+                }
+                continue;
+            }
+            if let Some(index) = control_device_index(name) {
+                if event.mask & inotify::IN_DELETE != 0 {
+                    self.remove(index);
+                } else {
+                    self.add(index);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle the events on all descriptors without blocking, queueing the
+    /// messages of the watched controls that changed.
+    fn process(&mut self) -> Result<(), subscribable::Error> {
+        loop {
+            let n = unsafe {
+                libc::poll(
+                    self.poll_fds.as_mut_ptr(),
+                    self.poll_fds.len() as libc::nfds_t,
+                    0,
+                )
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(subscribable::Error::new(format!("poll failed: {}", e), true));
+            }
+
+            if self.poll_fds[0].revents != 0 {
+                // The descriptors may be rebuilt, so the reactor is asked to
+                // wait for them again.
+                return self.hotplug();
+            }
+
+            // Descriptors of each card start after those of the preceding
+            // cards.
+            let mut offset = 1;
+            let mut removed = None;
+            for card in &mut self.cards {
+                let fds = &self.poll_fds[offset..offset + card.fds.len()];
+                offset += card.fds.len();
+
+                let flags = match card.ctl.revents(fds) {
+                    Ok(flags) => flags,
+                    Err(_) => {
+                        removed = Some(card.index);
+                        break;
+                    }
+                };
+                if flags.is_empty() {
+                    continue;
+                }
+                if flags.intersects(POLLERR | POLLHUP) {
+                    // The card is going away; its device may not have been
+                    // deleted yet.
+                    removed = Some(card.index);
+                    break;
+                }
+                if flags != POLLIN {
+                    return Err(subscribable::Error::from(format!(
+                        "Got unexpected poll flags for {}: {:#?}",
+                        card.name, flags
+                    )));
+                }
+
+                card.ctl.read().map_err(|e| {
+                    format!("Cannot read events of sound card {}: {}", card.name, e)
+                })?;
+
+                // Report jacks first, since plugging in headphones may also
+                // change the volume of some controls.
+                let jacks = card
+                    .update_jacks()
+                    .map_err(|e| format!("Cannot read jacks of {}: {}", card.name, e))?;
+                self.pending.extend(jacks);
+
+                let (name, hwid) = (&card.name, &card.hwid);
+                let watched = card.playback.iter_mut().chain(card.capture.iter_mut());
+                for watched in watched {
+                    let msg = watched.update(hwid).map_err(|e| {
+                        format!("Cannot read `{}` of {}: {}", watched.control, name, e)
+                    })?;
+                    self.pending.extend(msg);
+                }
+            }
+            if let Some(index) = removed {
+                self.remove(index);
+            }
+            return Ok(());
         }
     }
 }
 
-struct Master<'a>(Selem<'a>, Mixer);
-impl<'a> Master<'a> {
+// FIXME: Need to get a new mixer every time the volume changes for some
+// reason. This is a bit awkward and seems unnecessary.
+fn open_control<'a>(
+    hwid: &str,
+    name: &str,
+    index: u32,
+    direction: Direction,
+) -> alsa::Result<Control<'a>> {
+    let mixer = Mixer::new(hwid, false)?;
+
+    unsafe {
+        // Transmute because the lifetime of selem is not just the
+        // lifetime of the reference to the mixer, but that of the mixer
+        // itself, which is 'a. This is "safe" since Mixer may be moved
+        // in rust code without affecting the underlying FFI code (it's
+        // effectively a box).
+        let selem = mem::transmute::<Selem, Selem<'a>>(match mixer
+            .find_selem(&SelemId::new(name, index))
+        {
+            Some(selem) => Ok(selem),
+            None => Err(alsa::Error::new("find_selem", -1)),
+        }?);
+
+        Ok(Control(selem, mixer, direction))
+    }
+}
+
+struct Control<'a>(Selem<'a>, Mixer, Direction);
+impl<'a> Control<'a> {
     fn get_mute(&self) -> alsa::Result<bool> {
         let selem: &Selem = &self.0;
 
-        Ok(selem.get_playback_switch(SelemChannelId::Unknown)? == 0)
+        // Controls without a switch (e.g. `PCM` on many cards) cannot be muted
+        Ok(match self.2 {
+            Direction::Playback => {
+                selem.has_playback_switch()
+                    && selem.get_playback_switch(SelemChannelId::Unknown)? == 0
+            }
+            Direction::Capture => {
+                selem.has_capture_switch()
+                    && selem.get_capture_switch(SelemChannelId::Unknown)? == 0
+            }
+        })
     }
 
     fn get_volume(&self) -> alsa::Result<f64> {
         let selem: &Selem = &self.0;
 
-        let range = selem.get_playback_db_range();
+        let (range, volume) = match self.2 {
+            Direction::Playback => (
+                selem.get_playback_db_range(),
+                selem.get_playback_vol_db(SelemChannelId::Unknown)?,
+            ),
+            Direction::Capture => (
+                selem.get_capture_db_range(),
+                selem.get_capture_vol_db(SelemChannelId::Unknown)?,
+            ),
+        };
         let range = ((range.0).0, (range.1).0); // get interior i64 mB value
-        let mut volume: f64 = selem.get_playback_vol_db(SelemChannelId::Unknown)?.0 as f64;
+        let mut volume: f64 = volume.0 as f64;
 
         // The following performs alsamixer-style volume mapping, as seen in
         // https://github.com/bear24rw/alsa-utils/blob/master/alsamixer/volume_mapping.c
@@ -134,56 +711,108 @@ impl<'a> Master<'a> {
     }
 }
 
-pub struct Subscription();
-impl Subscribable for Subscription {
-    type Params = ();
+pub struct Subscription(Cards);
+impl Reactive for Subscription {
+    type Params = Config;
 
-    fn poll_factory(_params: Self::Params) -> Result<Box<PollFn>, String> {
-        let mut poll_fds: Vec<pollfd> = Vec::new();
-        let mut cards: Vec<Card> = alsa::card::Iter::new()
-            .filter_map(|card| match Card::new(card.unwrap(), &mut poll_fds) {
-                Ok(card) => Some(card),
-                Err(_) => None,
-            }).collect();
+    fn start(config: Self::Params) -> Result<Self, String> {
+        Ok(Subscription(Cards::new(config)?))
+    }
 
-        err_if!(
-            cards.len() == 0,
-            "Failed to find any sound cards with a master volume.".to_string()
+    fn fds(&self) -> Vec<RawFd> {
+        self.0.poll_fds.iter().map(|poll_fd| poll_fd.fd).collect()
+    }
+
+    fn ready(&mut self, msgs: &mut Vec<ui::Msg>) -> Result<(), subscribable::Error> {
+        let result = self.0.process();
+        msgs.extend(self.0.pending.drain(..));
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use toml;
+
+    use super::*;
+
+    #[test]
+    fn parses_control_device_names() {
+        let cases = vec![
+            ("controlC0", Some(0)),
+            ("controlC12", Some(12)),
+            ("controlC", None),
+            ("controlCx", None),
+            ("pcmC0D0p", None),
+            ("timer", None),
+        ];
+        for (file_name, index) in cases {
+            assert_eq!(
+                control_device_index(&OsString::from(file_name)),
+                index,
+                "{}",
+                file_name
+            );
+        }
+    }
+
+    #[test]
+    fn card_is_selected_by_index_or_name() {
+        let config = |toml: &str| toml::from_str::<Config>(toml).unwrap().card;
+        // All cards are watched by default, including those plugged in later
+        assert_eq!(config(""), None);
+        assert_eq!(config("card = 1"), Some(CardSelector::Index(1)));
+        assert_eq!(
+            config("card = \"hw:1\""),
+            Some(CardSelector::Name("hw:1".into()))
         );
 
-        Ok(Box::new(move || {
-            loop {
-                poll(&mut poll_fds, -1).unwrap();
-
-                for card in &mut cards {
-                    let flags = card
-                        .ctl
-                        .revents(&poll_fds[card.fd_i..card.fd_i + card.fd_n])
-                        .unwrap();
-                    if !flags.is_empty() {
-                        if flags == POLLIN {
-                            card.ctl.read().unwrap();
-                            let master = card.get_master().unwrap();
-                            let muted = master.get_mute().unwrap();
-                            let volume = master.get_volume().unwrap();
-
-                            if muted && card.volume != -1.0 {
-                                card.volume = -1.0;
-                                return Ok(ui::ShowBool("", "Muted"));
-                            } else if !muted && volume != card.volume {
-                                card.volume = volume;
-                                return Ok(ui::ShowPercent("", card.volume));
-                            }
-                        } else {
-                            return Err(subscribable::Error::from(format!(
-                                "Got unexpected poll flags for {}: {:#?}",
-                                card.name, flags
-                            )));
-                        }
-                    }
-                }
-                // if no event matched the criterea, loop to poll again
-            } // while true
-        }))
+        let select = |selector: &str, index| {
+            config(selector).unwrap().selects(
+                index,
+                "PCH",
+                "HDA Intel PCH",
+                "HDA Intel PCH at 0xf7f10000",
+            )
+        };
+        for selector in &["card = 1", "card = \"1\"", "card = \"hw:1\""] {
+            assert!(select(selector, 1), "{}", selector);
+            assert!(!select(selector, 0), "{}", selector);
+        }
+        for selector in &[
+            "card = \"hw:PCH\"",
+            "card = \"PCH\"",
+            "card = \"HDA Intel PCH\"",
+            "card = \"HDA Intel PCH at 0xf7f10000\"",
+        ] {
+            assert!(select(selector, 0), "{}", selector);
+        }
+        for selector in &[
+            "card = \"hw:NVidia\"",
+            "card = \"HDA Intel\"",
+            "card = \"hw:\"",
+        ] {
+            assert!(!select(selector, 0), "{}", selector);
+        }
+        // Names that cannot be read match nothing
+        assert!(!CardSelector::Name("".into()).selects(0, "", "", ""));
+    }
+
+    #[test]
+    fn guesses_jack_kind_from_name() {
+        let cases = vec![
+            ("Headphone Jack", JackKind::Headphone),
+            ("Front Headphone Jack", JackKind::Headphone),
+            ("Mic Jack", JackKind::Microphone),
+            ("Front Mic Jack", JackKind::Microphone),
+            ("Headset Mic Jack", JackKind::Microphone),
+            ("Line Out Jack", JackKind::LineOut),
+            ("HDMI/DP,pcm=3 Jack", JackKind::Other),
+        ];
+        for (name, kind) in cases {
+            assert_eq!(JackKind::from_name(name), kind, "{}", name);
+        }
     }
 }
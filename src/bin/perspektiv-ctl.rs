@@ -0,0 +1,162 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Client for perspektiv's control socket, for use in scripts:
+//
+//     perspektiv-ctl text "Caps Lock on"
+//     perspektiv-ctl percent 40 --caption "Keyboard backlight"
+//     perspektiv-ctl bool "Touchpad off" --icon input-touchpad-symbolic
+//     perspektiv-ctl hide
+//     perspektiv-ctl status
+
+#[macro_use]
+extern crate serde_json;
+
+use std::{
+    env,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    process,
+};
+
+use serde_json::Value;
+
+const USAGE: &str = "Usage:
+    perspektiv-ctl [--socket PATH] percent VALUE [--icon ICON] [--caption TEXT | --max MAX]
+    perspektiv-ctl [--socket PATH] bool LABEL [--icon ICON]
+    perspektiv-ctl [--socket PATH] text TEXT [--icon ICON]
+    perspektiv-ctl [--socket PATH] hide
+    perspektiv-ctl [--socket PATH] status
+
+VALUE is a percentage from 0 to 100, or up to MAX (e.g. 150) if given. `status`
+lists the modules that are running or waiting to be restarted. The socket
+defaults to $XDG_RUNTIME_DIR/perspektiv.sock.";
+
+/// Parsed command line
+struct Args {
+    socket: Option<PathBuf>,
+    command: Value,
+}
+
+fn parse_args(args: Vec<String>) -> Result<Args, String> {
+    let mut socket = None;
+    let mut icon = String::new();
+    let mut caption = None;
+    let mut max = None;
+    let mut positional = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--socket" | "--icon" | "--caption" | "--max" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))?;
+                match arg.as_str() {
+                    "--socket" => socket = Some(PathBuf::from(value)),
+                    "--icon" => icon = value,
+                    "--caption" => caption = Some(value),
+                    _ => {
+                        let value: f64 = value
+                            .parse()
+                            .map_err(|_| format!("Invalid percentage `{}`", value))?;
+                        max = Some(value / 100.0);
+                    }
+                }
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => positional.push(arg),
+        }
+    }
+
+    let command = match positional
+        .iter()
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["percent", value] => {
+            let value: f64 = value
+                .parse()
+                .map_err(|_| format!("Invalid percentage `{}`", value))?;
+            json!({
+                "command": "show_percent",
+                "icon": icon,
+                "value": value / 100.0,
+                "caption": caption,
+                "max": max,
+            })
+        }
+        ["bool", label] => json!({
+            "command": "show_bool",
+            "icon": icon,
+            "label": label,
+        }),
+        ["text", text] => json!({
+            "command": "show_text",
+            "icon": icon,
+            "text": text,
+        }),
+        ["hide"] => json!({ "command": "hide" }),
+        ["status"] => json!({ "command": "status" }),
+        _ => return Err(USAGE.to_string()),
+    };
+
+    Ok(Args { socket, command })
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let path = match args.socket {
+        Some(path) => path,
+        None => env::var_os("XDG_RUNTIME_DIR")
+            .map(|dir| PathBuf::from(dir).join("perspektiv.sock"))
+            .ok_or("$XDG_RUNTIME_DIR is not set; use --socket")?,
+    };
+
+    let mut stream = UnixStream::connect(&path)
+        .map_err(|e| format!("Cannot connect to {:?} (is perspektiv running?): {}", path, e))?;
+    writeln!(stream, "{}", args.command).map_err(|e| format!("Cannot send command: {}", e))?;
+
+    let mut response = String::new();
+    BufReader::new(stream)
+        .read_line(&mut response)
+        .map_err(|e| format!("Cannot read response: {}", e))?;
+    let response: Value = serde_json::from_str(&response)
+        .map_err(|e| format!("Invalid response `{}`: {}", response.trim(), e))?;
+
+    match response["ok"].as_bool() {
+        Some(true) => {
+            if let Some(modules) = response["modules"].as_object() {
+                for (name, health) in modules {
+                    println!("{}: {}", name, health.as_str().unwrap_or("unknown"));
+                }
+            }
+            Ok(())
+        }
+        _ => Err(response["error"]
+            .as_str()
+            .unwrap_or("Unknown error")
+            .to_string()),
+    }
+}
+
+fn main() {
+    let result = parse_args(env::args().skip(1).collect()).and_then(run);
+    if let Err(msg) = result {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
+}
@@ -14,54 +14,101 @@
 // this program. If not, see <https://www.gnu.org/licenses/>.
 
 extern crate dirs;
-extern crate toml;
 
 use std::{
-    default::Default, env, error::Error, fs, fs::File, io::prelude::*, path::PathBuf,
-    string::String,
+    collections::BTreeMap, default::Default, env, error::Error, fs, fs::File, io::prelude::*,
+    os::unix::io::{AsRawFd, RawFd}, path::{Path, PathBuf}, process, string::String,
 };
 
-// Currently unused, but I'm keeping it in case it comes in handy in the future.
-// I abandoned this solution because I believe that these semantics should be
-// syntactically enforced with proper enum usage etc.
-#[allow(unused_macros)]
+use threlm::Actor;
+use toml;
+
+use icons::Icon;
+use inotify;
+use inotify::Inotify;
+use modules;
+use reactor::Reactive;
+use subscribable;
+use ui;
+use validate;
+use validate::{Problem, Report};
+
+// Semantic checks that the types of the configuration cannot express. Each
+// adds a problem to the `validate::Report` given as first argument, naming the
+// options by the expressions, so sections must be bound to variables of the
+// same name (e.g. `window.opacity`).
 macro_rules! config_requires {
-    (@panic_msg $str:expr, $a:expr, $b:expr) => {
-        let a: String = stringify!($a)
-            .chars()
-            .skip_while(|c| *c != '.')
-            .skip(1)
-            .collect();
-        let b: String = stringify!($b)
+    (@key $a:expr) => {
+        stringify!($a)
             .chars()
-            .skip_while(|c| *c != '.')
-            .skip(1)
-            .collect();
-        panic!(format!($str, a = a, b = b))
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>()
     };
 
-    (not_both $a:expr, $b:expr) => {
+    ($report:expr, not_both $a:expr, $b:expr) => {
         if $a.is_some() && $b.is_some() {
-            config_requires!(@panic_msg
-                                     "Configuration option `{a}` conflicts with option `{b}`.
-  help: Remove either `{a}` or `{b}` from your configuration.",
-                                     $a, $b
-                    );
+            let (a, b) = (config_requires!(@key $a), config_requires!(@key $b));
+            $report.push(
+                Problem::error(a.clone(), format!("conflicts with option `{}`", b))
+                    .suggest(format!("Remove either `{}` or `{}` from your configuration.", a, b)),
+            );
         }
     };
 
-    (one_of $a:expr, $b:expr) => {
+    ($report:expr, one_of $a:expr, $b:expr) => {
         if $a.is_none() && $b.is_none() {
-            config_requires!(@panic_msg
-                                     "Configuration requires one of `{a}` or `{b}` to be set.",
-                                     $a, $b
-                    );
+            let (a, b) = (config_requires!(@key $a), config_requires!(@key $b));
+            $report.push(
+                Problem::error("", format!("requires one of `{}` or `{}` to be set", a, b))
+                    .suggest(format!("Set either `{}` or `{}`.", a, b)),
+            );
         }
     };
 
-    (either $a:expr, $b:expr) => {
-        config_requires!(one_of $a, $b);
-        config_requires!(not_both $a, $b);
+    ($report:expr, either $a:expr, $b:expr) => {
+        config_requires!($report, one_of $a, $b);
+        config_requires!($report, not_both $a, $b);
+    };
+
+    // For signed options; unsigned ones are `nonzero`
+    ($report:expr, positive $a:expr, $unit:expr) => {
+        if $a <= 0 {
+            $report.push(
+                Problem::error(config_requires!(@key $a), format!("must be positive, but is {}", $a))
+                    .suggest(format!("Use a number of {} greater than 0.", $unit)),
+            );
+        }
+    };
+
+    ($report:expr, nonzero $a:expr, $unit:expr) => {
+        if $a == 0 {
+            $report.push(
+                Problem::error(config_requires!(@key $a), "must not be 0")
+                    .suggest(format!("Use a number of {} greater than 0.", $unit)),
+            );
+        }
+    };
+
+    ($report:expr, at_most $a:expr, $max:expr) => {
+        if $a > $max {
+            $report.push(
+                Problem::error(
+                    config_requires!(@key $a),
+                    format!("must be at most {}, but is {}", $max, $a),
+                ).suggest(format!("Use a value from 0 to {}.", $max)),
+            );
+        }
+    };
+
+    ($report:expr, at_least $a:expr, $min:expr) => {
+        if $a < $min {
+            $report.push(
+                Problem::error(
+                    config_requires!(@key $a),
+                    format!("must be at least {}, but is {}", $min, $a),
+                ).suggest(format!("Use a value of {} or more.", $min)),
+            );
+        }
     };
 }
 
@@ -69,9 +116,19 @@ const DEFAULT_CONF: &'static str = include_str!("../default.toml");
 
 #[derive(Deserialize)]
 pub struct Config {
+    /// The file this configuration was read from
+    #[serde(skip)]
+    pub path: PathBuf,
     pub window: Window,
     pub boolean: Boolean,
     pub percentage: Percentage,
+    #[serde(default)]
+    pub modules: Modules,
+    #[serde(default)]
+    pub restart: Restart,
+    /// Replacements for icons of the theme, by icon name
+    #[serde(default)]
+    pub icons: BTreeMap<String, IconOverride>,
 }
 
 #[derive(Deserialize)]
@@ -81,6 +138,14 @@ pub struct Window {
 
     pub margin_horiz: MarginHoriz,
     pub margin_vert: MarginVert,
+    /// The part of the monitor the margins are measured in
+    #[serde(default)]
+    pub area: Area,
+    /// The monitor the margins are measured on
+    #[serde(default)]
+    pub placement: Placement,
+    /// Name of the monitor for `Placement::Output`, e.g. `HDMI-1`
+    pub output: Option<String>,
 
     pub duration: u32,
 
@@ -89,6 +154,14 @@ pub struct Window {
     pub css: Option<PathBuf>,
 
     pub opacity: u32,
+
+    /// Size of icons from the icon theme or image files in pixels
+    #[serde(default = "default_icon_size")]
+    pub icon_size: i32,
+}
+
+fn default_icon_size() -> i32 {
+    64
 }
 
 #[derive(Deserialize)]
@@ -99,24 +172,159 @@ pub struct Boolean {
 #[derive(Deserialize)]
 pub struct Percentage {
     pub show_numeric: bool,
+    #[serde(default)]
+    pub thresholds: Thresholds,
 }
 
+/// Percentages at which the scale gets the CSS class `critical` (at or below
+/// `critical`), `low` (at or below `low`) or `high` (above `high`).
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Thresholds {
+    pub critical: u32,
+    pub low: u32,
+    pub high: u32,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            critical: 5,
+            low: 20,
+            high: 100,
+        }
+    }
+}
+
+impl Thresholds {
+    /// CSS class of the scale for a value from 0.0 to 1.0, or above if boosted
+    pub fn class(&self, value: f64) -> Option<&'static str> {
+        let percent = value * 100.0;
+        if percent <= self.critical as f64 {
+            Some("critical")
+        } else if percent <= self.low as f64 {
+            Some("low")
+        } else if percent > self.high as f64 {
+            Some("high")
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Modules {
+    // Configuration files predating runtime module selection have no
+    // `[modules]` section; run everything that was compiled in for those.
+    #[serde(default = "modules::default_names")]
+    pub enabled: Vec<String>,
+
+    /// Module specific sections, such as `[modules.alsa_volume]`. These are
+    /// only deserialized by the modules themselves, see
+    /// `modules::Module::subscribe`.
+    #[serde(flatten)]
+    pub sections: BTreeMap<String, toml::Value>,
+}
+
+impl Default for Modules {
+    fn default() -> Self {
+        Modules {
+            enabled: modules::default_names(),
+            sections: BTreeMap::new(),
+        }
+    }
+}
+
+/// Restart policy for failed modules. The delay between restarts grows
+/// exponentially from `initial_delay` up to `max_delay` (both in milliseconds).
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Restart {
+    pub initial_delay: u64,
+    pub max_delay: u64,
+    pub multiplier: f64,
+    /// Number of restarts without the module recovering before giving up
+    pub max_restarts: u32,
+    /// Milliseconds after which a running module has recovered, even if it
+    /// has not shown anything
+    pub reset_after: u64,
+}
+
+impl Default for Restart {
+    fn default() -> Self {
+        Restart {
+            initial_delay: 500,
+            max_delay: 60_000,
+            multiplier: 2.0,
+            max_restarts: 10,
+            reset_after: 60_000,
+        }
+    }
+}
+
+/// What to show instead of an icon, e.g. `{ glyph = "" }` for a character of
+/// an icon font, or `{ image = "./volume.svg" }`. Exactly one must be set.
+#[derive(Clone, Deserialize)]
+pub struct IconOverride {
+    pub glyph: Option<String>,
+    pub image: Option<PathBuf>,
+}
+
+/// Which monitor to show the popup on. It is chosen again every time the popup
+/// is shown.
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+pub enum Placement {
+    /// The primary monitor
+    Primary,
+    /// The monitor under the mouse pointer
+    Pointer,
+    /// The monitor with the focused window, according to the window manager
+    Focused,
+    /// The monitor named by `window.output`
+    Output,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Placement::Primary
+    }
+}
+
+#[derive(Clone, Copy, Deserialize, PartialEq)]
+pub enum Area {
+    /// The whole monitor
+    Monitor,
+    /// The monitor without the space reserved by panels
+    WorkArea,
+}
+
+impl Default for Area {
+    fn default() -> Self {
+        Area::Monitor
+    }
+}
+
+/// The margin of `Center` is the offset from the centre, towards the right.
 #[derive(Deserialize)]
 #[serde(tag = "anchor", content = "margin")]
 pub enum MarginHoriz {
     Left(i32),
     Right(i32),
+    Center(i32),
 }
 
+/// The margin of `Center` is the offset from the centre, downwards.
 #[derive(Deserialize)]
 #[serde(tag = "anchor", content = "margin")]
 pub enum MarginVert {
     Top(i32),
     Bottom(i32),
+    Center(i32),
 }
 
-pub fn read() -> Config {
-    let config_dir: PathBuf = match env::var("PERSPEKTIV_CONFIG") {
+/// The directory holding the configuration, created if it does not exist.
+fn dir() -> PathBuf {
+    match env::var("PERSPEKTIV_CONFIG") {
         Ok(path) => PathBuf::from(path),
         Err(_) => match dirs::config_dir() {
             Some(ref path) => {
@@ -128,8 +336,11 @@ pub fn read() -> Config {
             },
             _ => panic!("Cannot obtain config file: No PERSPEKTIV_CONFIG set, and no user home directory found!"),
         }
-    };
+    }
+}
 
+pub fn read() -> Config {
+    let config_dir = dir();
     let config_path = config_dir.join("config.toml");
     // Read config as TOML string
     let config: String = if !config_path.exists() {
@@ -160,28 +371,265 @@ pub fn read() -> Config {
         buffer
     };
 
-    // Parse config
-    let mut config: Config = toml::from_str(&config).expect("Failed to parse TOML");
+    let (config, report) = check(&config, &config_path);
+    report.log();
+    match config {
+        Some(config) => config,
+        None => {
+            error!("Invalid configuration file {:?}; exiting.", config_path);
+            process::exit(1);
+        }
+    }
+}
+
+/// Read the configuration file again. Unlike `read`, this never exits, so that
+/// the running configuration can be kept if the file became invalid.
+pub fn reload() -> Result<Config, String> {
+    let config_path = dir().join("config.toml");
+    let mut buffer = String::new();
+    File::open(&config_path)
+        .and_then(|mut file| file.read_to_string(&mut buffer))
+        .map_err(|e| format!("Failed to read configuration file {:?}: {}", config_path, e))?;
+
+    let (config, report) = check(&buffer, &config_path);
+    report.log();
+    config.ok_or_else(|| format!("Configuration file {:?} is invalid", config_path))
+}
+
+/// Check the configuration file at `path`, or the one perspektiv would read,
+/// for `--check-config`. Returns the exit status: 1 if it has errors.
+pub fn check_file(path: Option<PathBuf>) -> i32 {
+    let path = path.unwrap_or_else(|| dir().join("config.toml"));
+    if path.exists() {
+        let mut buffer = String::new();
+        if let Err(e) = File::open(&path).and_then(|mut file| file.read_to_string(&mut buffer)) {
+            eprintln!("error: Failed to read configuration file {:?}: {}", path, e);
+            return 1;
+        }
+        let (_, report) = check(&buffer, &path);
+        eprint!("{}", report);
+        if report.has_errors() {
+            1
+        } else {
+            println!("{:?} is valid.", path);
+            0
+        }
+    } else {
+        println!(
+            "{:?} does not exist; perspektiv will create it with default values.",
+            path
+        );
+        0
+    }
+}
+
+/// Parse and validate the configuration read from `config_path`. The
+/// configuration is only returned if the report has no errors.
+pub fn check(config: &str, config_path: &Path) -> (Option<Config>, Report) {
+    let mut report = Report::new(config_path, config);
+    let value: toml::Value = match toml::from_str(config) {
+        Ok(value) => value,
+        Err(e) => {
+            report.push(Problem::from_toml("", &e));
+            return (None, report);
+        }
+    };
+
+    // Each section on its own, so that problems in all of them are reported
+    validate::unknown_keys::<Config>(&mut report, "", &value);
+    if let Some(section) = value.get("window") {
+        if let Some(window) = validate::table::<Window>(&mut report, "window", section) {
+            config_requires!(report, positive window.width, "pixels");
+            config_requires!(report, positive window.height, "pixels");
+            config_requires!(report, nonzero window.duration, "milliseconds");
+            config_requires!(report, at_most window.opacity, 100);
+            config_requires!(report, positive window.icon_size, "pixels");
+            match (window.placement, &window.output) {
+                (Placement::Output, &None) => report.push(
+                    Problem::error("window.placement", "requires `output` to be set")
+                        .suggest("Set `output` to the name of a monitor, e.g. \"HDMI-1\"."),
+                ),
+                (Placement::Output, &Some(_)) | (_, &None) => {}
+                (_, &Some(_)) => report.push(
+                    Problem::warning("window.output", "is only used with `placement = \"Output\"`")
+                        .suggest("Set `placement = \"Output\"`, or remove `output`."),
+                ),
+            }
+        }
+        if let Some(margin) = section.get("margin_horiz") {
+            validate::unknown_keys::<MarginHoriz>(&mut report, "window.margin_horiz", margin);
+        }
+        if let Some(margin) = section.get("margin_vert") {
+            validate::unknown_keys::<MarginVert>(&mut report, "window.margin_vert", margin);
+        }
+    }
+    if let Some(boolean) = value.get("boolean") {
+        validate::table::<Boolean>(&mut report, "boolean", boolean);
+    }
+    if let Some(section) = value.get("percentage") {
+        let percentage = validate::table::<Percentage>(&mut report, "percentage", section);
+        if let Some(percentage) = percentage {
+            config_requires!(
+                report,
+                at_least percentage.thresholds.low,
+                percentage.thresholds.critical
+            );
+            config_requires!(
+                report,
+                at_least percentage.thresholds.high,
+                percentage.thresholds.low
+            );
+        }
+        if let Some(thresholds) = section.get("thresholds") {
+            validate::unknown_keys::<Thresholds>(&mut report, "percentage.thresholds", thresholds);
+        }
+    }
+    if let Some(section) = value.get("restart") {
+        if let Some(restart) = validate::table::<Restart>(&mut report, "restart", section) {
+            config_requires!(report, at_least restart.multiplier, 1.0);
+            config_requires!(report, at_least restart.max_delay, restart.initial_delay);
+        }
+    }
+    if let Some(modules) = value.get("modules") {
+        validate::table::<Modules>(&mut report, "modules", modules);
+        modules::check(&mut report, modules);
+    }
+    if let Some(icons) = value.get("icons") {
+        let known: Vec<&str> = Icon::ALL.iter().map(|icon| icon.name()).collect();
+        for (name, section) in icons.as_table().into_iter().flat_map(|table| table.iter()) {
+            let key = format!("icons.{}", name);
+            if Icon::from_name(name).is_none() {
+                report.push(validate::unknown_key("icons", name, &known));
+            }
+            if let Some(icon) = validate::table::<IconOverride>(&mut report, &key, section) {
+                if icon.glyph.is_some() == icon.image.is_some() {
+                    report.push(
+                        Problem::error(key, "needs exactly one of `glyph` or `image`")
+                            .suggest("Set either `glyph` or `image`, e.g. `{ glyph = \"x\" }`."),
+                    );
+                }
+            }
+        }
+    }
+    if report.has_errors() {
+        return (None, report);
+    }
+
+    // Reports missing sections
+    let mut config: Config = match value.try_into() {
+        Ok(config) => config,
+        Err(e) => {
+            report.push(Problem::from_toml("", &e));
+            return (None, report);
+        }
+    };
 
     // Modify config
     // expand css path
-    config = match config {
-        Config {
-            window: Window {
-                css: Some(path), ..
-            },
-            ..
-        } => Config {
-            window: Window {
-                css: Some(expand_path(path, &config_dir)),
-                ..config.window
-            },
-            ..config
-        },
-        _ => config,
-    };
+    if let Some(path) = config.window.css.take() {
+        let path = expand_path(path, &config_path.parent().unwrap().to_path_buf());
+        if !path.is_file() {
+            report.push(
+                Problem::warning("window.css", format!("CSS file {:?} does not exist", path))
+                    .suggest("Create the file, or remove `css` to use the GTK theme only."),
+            );
+        }
+        config.window.css = Some(path);
+    }
+    // expand image paths
+    for (name, icon) in config.icons.iter_mut() {
+        if let Some(path) = icon.image.take() {
+            let path = expand_path(path, &config_path.parent().unwrap().to_path_buf());
+            if !path.is_file() {
+                report.push(
+                    Problem::warning(
+                        format!("icons.{}", name),
+                        format!("image file {:?} does not exist", path),
+                    ).suggest("Create the file, or remove the override to use the icon theme."),
+                );
+            }
+            icon.image = Some(path);
+        }
+    }
+    config.path = config_path.to_path_buf();
 
-    return config;
+    (Some(config), report)
+}
+
+impl Config {
+    /// Files that make up the configuration
+    pub fn files(&self) -> Vec<PathBuf> {
+        let mut files = vec![self.path.clone()];
+        files.extend(self.window.css.clone());
+        files
+    }
+}
+
+/// Watches the files of the configuration, asking the UI to reload it when any
+/// of them is written. Their directories are watched rather than the files, as
+/// editors often replace files instead of writing to them. Files in directories
+/// that do not exist are not watched; the configuration file still is, so
+/// correcting their paths reloads it.
+pub struct Watcher {
+    inotify: Inotify,
+    /// Watch descriptors of the directories, and the names of the files in them
+    files: Vec<(i32, PathBuf)>,
+}
+
+impl Reactive for Watcher {
+    /// The files to watch
+    type Params = Vec<PathBuf>;
+
+    fn start(files: Self::Params) -> Result<Self, String> {
+        let inotify = Inotify::new().map_err(|e| format!("Cannot initialise inotify: {}", e))?;
+        let mut watched = Vec::new();
+        for file in files {
+            let (dir, name) = match (file.parent(), file.file_name()) {
+                (Some(dir), Some(name)) => (dir, PathBuf::from(name)),
+                _ => continue,
+            };
+            let dir = if dir == Path::new("") { Path::new(".") } else { dir };
+            if !dir.is_dir() {
+                warn!("Not watching {:?} for changes, as {:?} does not exist", name, dir);
+                continue;
+            }
+            let wd = inotify
+                .add_watch(dir, inotify::IN_CLOSE_WRITE | inotify::IN_MOVED_TO)
+                .map_err(|e| format!("Cannot watch {:?}: {}", dir, e))?;
+            watched.push((wd, name));
+        }
+
+        Ok(Watcher {
+            inotify,
+            files: watched,
+        })
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.inotify.as_raw_fd()]
+    }
+
+    fn ready(&mut self, msgs: &mut Vec<ui::Msg>) -> Result<(), subscribable::Error> {
+        let events = self
+            .inotify
+            .read_events()
+            .map_err(|e| format!("Cannot read inotify events: {}", e))?;
+        let written = events.iter().any(|event| {
+            self.files.iter().any(|&(wd, ref name)| {
+                event.wd == wd && event.name.as_ref().map_or(false, |n| Path::new(n) == name)
+            })
+        });
+        // Once for all files written at the same time
+        if written {
+            msgs.push(ui::Reload);
+        }
+        Ok(())
+    }
+}
+
+/// Reload the configuration whenever one of its files is written.
+pub fn watch(config: &Config, actor: Actor<ui::Window>) {
+    Watcher::subscribe(actor, "config", config.files(), config.restart.clone());
 }
 
 // TODO: Write tests, check standards compliance
@@ -196,3 +644,48 @@ fn expand_path(path: PathBuf, working_dir: &PathBuf) -> PathBuf {
         path
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_configuration_is_valid() {
+        let (config, report) = check(DEFAULT_CONF, Path::new("config.toml"));
+        assert!(config.is_some());
+        assert!(report.problems.is_empty());
+    }
+
+    #[test]
+    fn rejects_zero_duration() {
+        let source = DEFAULT_CONF.replace("duration = 1200", "duration = 0");
+        let (config, report) = check(&source, Path::new("config.toml"));
+        assert!(config.is_none());
+        assert_eq!(report.problems.len(), 1);
+        let problem = &report.problems[0];
+        assert_eq!(problem.key, "window.duration");
+        assert_eq!(problem.message, "must not be 0");
+        assert_eq!(
+            problem.suggestion,
+            "Use a number of milliseconds greater than 0."
+        );
+    }
+
+    #[test]
+    fn watches_only_existing_directories() {
+        let dir = env::temp_dir().join(format!("perspektiv-watcher-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let files = vec![
+            dir.join("config.toml"),
+            dir.join("missing").join("style.css"),
+        ];
+        let mut watcher = Watcher::start(files).ok().unwrap();
+
+        fs::write(dir.join("config.toml"), "").unwrap();
+        let mut msgs = Vec::new();
+        watcher.ready(&mut msgs).ok().unwrap();
+        assert_eq!(msgs, vec![ui::Reload]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
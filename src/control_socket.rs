@@ -0,0 +1,482 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Lets other programs (e.g. `perspektiv-ctl` in hotkey scripts) show popups.
+// Clients send one JSON command per line and receive one JSON response per
+// line; see the README for the protocol. The listening socket and all clients
+// are non-blocking and served by the reactor, which waits for clients to accept
+// responses that could not be written at once.
+
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+
+use serde_json;
+
+use reactor::Reactive;
+use subscribable;
+use supervisor;
+use ui;
+
+/// Name of the socket in `$XDG_RUNTIME_DIR`
+pub const SOCKET_NAME: &str = "perspektiv.sock";
+/// Clients are disconnected if a line grows longer than this, or if they leave
+/// as many bytes of responses unread.
+const MAX_LINE: usize = 64 * 1024;
+
+/// Configuration section `[modules.control_socket]`
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Path of the socket. If unset, `$XDG_RUNTIME_DIR/perspektiv.sock` is
+    /// used.
+    pub path: Option<PathBuf>,
+}
+
+/// A command sent by a client, e.g.
+/// `{"command": "show_percent", "icon": "volume-medium", "value": 0.5}`
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    ShowPercent {
+        #[serde(default)]
+        icon: String,
+        /// 0.0 to 1.0, or up to `max`
+        value: f64,
+        caption: Option<String>,
+        /// Allows values above 1.0, e.g. 1.5 for 150%
+        max: Option<f64>,
+    },
+    ShowBool {
+        #[serde(default)]
+        icon: String,
+        /// Shown if `boolean.show_label` is set
+        label: String,
+    },
+    ShowText {
+        #[serde(default)]
+        icon: String,
+        text: String,
+    },
+    Hide,
+    /// Ask for the health of the modules instead of showing a popup
+    Status,
+}
+
+impl Command {
+    fn into_msg(self) -> Result<ui::Msg, String> {
+        Ok(match self {
+            Command::ShowPercent {
+                icon,
+                value,
+                caption,
+                max,
+            } => {
+                if caption.is_some() && max.is_some() {
+                    return Err("`caption` cannot be combined with `max`".to_string());
+                }
+                let limit = max.unwrap_or(1.0);
+                err_if!(
+                    !limit.is_finite() || limit < 1.0,
+                    format!("Invalid maximum `{}`, expected a number of at least 1", limit)
+                );
+                // Also rejects NaN
+                err_if!(
+                    !(value >= 0.0 && value <= limit),
+                    format!("Invalid value `{}`, expected a number from 0 to {}", value, limit)
+                );
+                match (caption, max) {
+                    (Some(caption), _) => {
+                        ui::ShowCaptionedPercent(icon.into(), value, caption.into())
+                    }
+                    (None, Some(max)) => ui::ShowBoostedPercent(icon.into(), value, max),
+                    (None, None) => ui::ShowPercent(icon.into(), value),
+                }
+            }
+            Command::ShowBool { icon, label } => ui::ShowBool(icon.into(), label.into()),
+            Command::ShowText { icon, text } => ui::ShowText(icon.into(), text.into()),
+            Command::Hide => ui::Hide,
+            Command::Status => return Err("`status` does not show a popup".to_string()),
+        })
+    }
+}
+
+/// The path of the socket if none is configured.
+pub fn default_path() -> Result<PathBuf, String> {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Ok(PathBuf::from(dir).join(SOCKET_NAME)),
+        None => Err("$XDG_RUNTIME_DIR is not set; set `path` for the control socket".to_string()),
+    }
+}
+
+/// Bind to `path`, replacing a socket left behind by an earlier instance that
+/// is no longer running.
+fn bind(path: &Path) -> Result<UnixListener, String> {
+    if path.exists() {
+        err_if!(
+            UnixStream::connect(path).is_ok(),
+            format!("Another instance is already listening on {:?}", path)
+        );
+        fs::remove_file(path).map_err(|e| format!("Cannot remove stale socket {:?}: {}", path, e))?;
+    }
+    UnixListener::bind(path).map_err(|e| format!("Cannot bind to {:?}: {}", path, e))
+}
+
+/// Health of the modules by name, e.g. `{"alsa_volume": "running"}`
+fn status() -> serde_json::Value {
+    let modules = supervisor::health()
+        .into_iter()
+        .map(|(name, health)| (name.to_string(), json!(health.to_string())))
+        .collect();
+    serde_json::Value::Object(modules)
+}
+
+/// Run the command on a line sent by a client, returning the message to show
+/// and the response.
+fn execute(line: &str) -> (Option<ui::Msg>, serde_json::Value) {
+    let result = serde_json::from_str::<Command>(line)
+        .map_err(|e| format!("Invalid command: {}", e));
+    match result {
+        Ok(Command::Status) => (None, json!({ "ok": true, "modules": status() })),
+        Ok(command) => match command.into_msg() {
+            Ok(msg) => (Some(msg), json!({ "ok": true })),
+            Err(e) => (None, json!({ "ok": false, "error": e })),
+        },
+        Err(e) => (None, json!({ "ok": false, "error": e })),
+    }
+}
+
+struct Client {
+    stream: UnixStream,
+    /// Received bytes that do not form a complete line yet
+    buffer: Vec<u8>,
+    /// Responses that the client has not accepted yet
+    output: Vec<u8>,
+}
+
+impl Client {
+    fn new(stream: UnixStream) -> Self {
+        Client {
+            stream,
+            buffer: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Execute the complete lines the client has sent, and write the
+    /// responses. Returns false once the client has disconnected or
+    /// misbehaved.
+    fn serve(&mut self, msgs: &mut Vec<ui::Msg>) -> bool {
+        let mut chunk = [0u8; 4096];
+        let open = loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => break false,
+                Ok(n) => {
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                    self.execute_lines(msgs);
+                    // Only an incomplete line is left
+                    if self.buffer.len() > MAX_LINE {
+                        break false;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break true,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break false,
+            }
+        };
+        // Clients that have shut down their end still get the responses
+        self.flush() && open
+    }
+
+    fn execute_lines(&mut self, msgs: &mut Vec<ui::Msg>) {
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (msg, response) = execute(&line);
+            msgs.extend(msg);
+            self.output.extend_from_slice(response.to_string().as_bytes());
+            self.output.push(b'\n');
+        }
+    }
+
+    /// Write as much of the responses as the client accepts. Returns false if
+    /// the client is gone, or does not read its responses.
+    fn flush(&mut self) -> bool {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => return false,
+            }
+        }
+        self.output.len() <= MAX_LINE
+    }
+}
+
+pub struct Subscription {
+    path: PathBuf,
+    listener: UnixListener,
+    clients: Vec<Client>,
+}
+
+impl Reactive for Subscription {
+    type Params = Config;
+
+    fn start(config: Self::Params) -> Result<Self, String> {
+        let path = match config.path {
+            Some(path) => path,
+            None => default_path()?,
+        };
+        let listener = bind(&path)?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|e| format!("Cannot make {:?} non-blocking: {}", path, e))?;
+        info!("Listening for commands on {:?}", path);
+
+        Ok(Subscription {
+            path,
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        let clients = self.clients.iter().map(|client| client.stream.as_raw_fd());
+        Some(self.listener.as_raw_fd()).into_iter().chain(clients).collect()
+    }
+
+    fn writable_fds(&self) -> Vec<RawFd> {
+        self.clients
+            .iter()
+            .filter(|client| !client.output.is_empty())
+            .map(|client| client.stream.as_raw_fd())
+            .collect()
+    }
+
+    fn ready(&mut self, msgs: &mut Vec<ui::Msg>) -> Result<(), subscribable::Error> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if stream.set_nonblocking(true).is_ok() {
+                        self.clients.push(Client::new(stream));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    return Err(subscribable::Error::new(
+                        format!("Cannot accept connection on {:?}: {}", self.path, e),
+                        true,
+                    ))
+                }
+            }
+        }
+
+        // Clients that are not ready just find nothing to read, or cannot
+        // accept more output
+        let clients = self.clients.drain(..).collect::<Vec<_>>();
+        for mut client in clients {
+            if client.serve(msgs) {
+                self.clients.push(client);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Subscription {
+    /// Remove the socket, so that it can be bound again when the module is
+    /// restarted.
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        mem, thread,
+    };
+
+    use libc;
+
+    use super::*;
+
+    fn error(line: &str) -> String {
+        match execute(line) {
+            (None, response) => {
+                assert_eq!(response["ok"], json!(false));
+                response["error"].as_str().unwrap().to_string()
+            }
+            (Some(msg), _) => panic!("`{}` was accepted as {:?}", line, msg),
+        }
+    }
+
+    #[test]
+    fn executes_commands() {
+        let commands = vec![
+            (
+                r#"{"command": "show_percent", "icon": "volume-medium", "value": 0.5}"#,
+                ui::ShowPercent("volume-medium".into(), 0.5),
+            ),
+            (
+                r#"{"command": "show_percent", "value": 1, "caption": "Fan"}"#,
+                ui::ShowCaptionedPercent("".into(), 1.0, "Fan".into()),
+            ),
+            (
+                r#"{"command": "show_percent", "value": 1.5, "max": 1.5}"#,
+                ui::ShowBoostedPercent("".into(), 1.5, 1.5),
+            ),
+            (
+                r#"{"command": "show_bool", "icon": "wifi", "label": "On"}"#,
+                ui::ShowBool("wifi".into(), "On".into()),
+            ),
+            (
+                r#"{"command": "show_text", "text": "Hello"}"#,
+                ui::ShowText("".into(), "Hello".into()),
+            ),
+            (r#"{"command": "hide"}"#, ui::Hide),
+        ];
+        for (line, expected) in commands {
+            assert_eq!(execute(line), (Some(expected), json!({ "ok": true })));
+        }
+
+        let (msg, response) = execute(r#"{"command": "status"}"#);
+        assert_eq!(msg, None);
+        assert_eq!(response["ok"], json!(true));
+        assert!(response["modules"].is_object());
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        assert!(error("show_text Hello").starts_with("Invalid command: "));
+        assert!(error(r#"{"command": "blink"}"#).starts_with("Invalid command: "));
+        assert!(error(r#"{"command": "show_text"}"#).starts_with("Invalid command: "));
+        assert!(error(r#"{"command": "show_percent", "value": "full"}"#)
+            .starts_with("Invalid command: "));
+    }
+
+    #[test]
+    fn validates_percentages() {
+        let cases = vec![
+            (
+                r#"{"value": 1.2}"#,
+                "Invalid value `1.2`, expected a number from 0 to 1",
+            ),
+            (
+                r#"{"value": -0.1}"#,
+                "Invalid value `-0.1`, expected a number from 0 to 1",
+            ),
+            (
+                r#"{"value": 2, "max": 1.5}"#,
+                "Invalid value `2`, expected a number from 0 to 1.5",
+            ),
+            (
+                r#"{"value": 0.5, "max": 0.5}"#,
+                "Invalid maximum `0.5`, expected a number of at least 1",
+            ),
+            (
+                r#"{"value": 0.5, "max": 1.5, "caption": "Fan"}"#,
+                "`caption` cannot be combined with `max`",
+            ),
+        ];
+        for (fields, expected) in cases {
+            let line = fields.replace("{", r#"{"command": "show_percent", "#);
+            let e = error(&line);
+            assert!(e.starts_with(expected), "`{}` failed with `{}`", line, e);
+        }
+    }
+
+    #[test]
+    fn serves_complete_lines() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut client = Client::new(stream);
+        let mut msgs = Vec::new();
+
+        // An incomplete line waits for the rest
+        peer.write_all(b"{\"command\": \"hide\"}\n\n{\"command\": ")
+            .unwrap();
+        assert!(client.serve(&mut msgs));
+        peer.write_all(b"\"blink\"}\n").unwrap();
+        assert!(client.serve(&mut msgs));
+        assert_eq!(msgs, vec![ui::Hide]);
+
+        let mut responses = BufReader::new(peer.try_clone().unwrap()).lines();
+        assert_eq!(responses.next().unwrap().unwrap(), r#"{"ok":true}"#);
+        assert!(responses
+            .next()
+            .unwrap()
+            .unwrap()
+            .starts_with(r#"{"error":"Invalid command: "#));
+
+        // Overlong lines disconnect the client
+        peer.write_all(&vec![b' '; MAX_LINE + 1]).unwrap();
+        assert!(!client.serve(&mut msgs));
+    }
+
+    #[test]
+    fn keeps_responses_until_the_client_accepts_them() {
+        let (stream, mut peer) = UnixStream::pair().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        // Let the socket buffer fill up quickly
+        let size: libc::c_int = 4096;
+        unsafe {
+            libc::setsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_SNDBUF,
+                &size as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+        }
+        let mut client = Client::new(stream);
+        let mut msgs = Vec::new();
+
+        let commands = 2000;
+        for _ in 0..commands {
+            peer.write_all(b"{\"command\": \"hide\"}\n").unwrap();
+            assert!(client.serve(&mut msgs));
+        }
+        assert!(!client.output.is_empty());
+        assert_eq!(msgs.len(), commands);
+
+        let reader = thread::spawn(move || {
+            BufReader::new(peer)
+                .lines()
+                .take(commands)
+                .filter(|line| line.as_ref().unwrap() == r#"{"ok":true}"#)
+                .count()
+        });
+        while !client.output.is_empty() {
+            assert!(client.flush());
+        }
+        assert_eq!(reader.join().unwrap(), commands);
+    }
+}
@@ -0,0 +1,429 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Exposes perspektiv on the session bus as `org.perspektiv.Osd`, see the README
+// for the interface. The reactor waits for the connection, and for messages
+// sent by all modules, which are re-emitted as `Event` signals. Method calls
+// queue up messages, which are handed out once the connection is processed.
+
+extern crate dbus;
+extern crate dbus_tree;
+
+use std::{
+    collections::VecDeque,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{mpsc::Receiver, Arc, Mutex},
+    time::Duration,
+};
+
+use self::dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply;
+use self::dbus::blocking::Connection;
+use self::dbus::channel::{BusType, Channel, Sender};
+use self::dbus::strings::{Interface, Path};
+use self::dbus::Message;
+use self::dbus_tree::{Factory, MTSync, MethodErr, MethodInfo, Signal};
+
+use reactor::{EventFd, Reactive};
+use subscribable;
+use ui;
+
+pub const BUS_NAME: &str = "org.perspektiv.Osd";
+pub const OBJECT_PATH: &str = "/org/perspektiv/Osd";
+pub const INTERFACE: &str = "org.perspektiv.Osd";
+
+/// Configuration section `[modules.dbus_service]`
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Address of the session bus, instead of `$DBUS_SESSION_BUS_ADDRESS`
+    pub address: Option<String>,
+    /// Emit an `Event` signal for every message of any module.
+    pub events: bool,
+    /// Take over the bus name if another process owns it.
+    pub replace: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: None,
+            events: true,
+            replace: false,
+        }
+    }
+}
+
+type Queue = Arc<Mutex<VecDeque<ui::Msg>>>;
+
+/// Handle a method call by queueing the message built from its arguments.
+fn show<F>(
+    queue: &Queue,
+    build: F,
+) -> impl Fn(&MethodInfo<MTSync<()>, ()>) -> Result<Vec<Message>, MethodErr>
+where
+    F: Fn(&Message) -> Result<ui::Msg, MethodErr>,
+{
+    let queue = queue.clone();
+    move |m| {
+        queue.lock().unwrap().push_back(build(m.msg)?);
+        Ok(vec![m.msg.method_return()])
+    }
+}
+
+/// Arguments of the `Event` signal for a message: kind, icon, label and value.
+fn event_args(msg: ui::Msg) -> Option<(&'static str, ui::Text, ui::Text, f64)> {
+    match msg {
+        ui::ShowPercent(icon, value) => Some(("percent", icon, "".into(), value)),
+        ui::ShowCaptionedPercent(icon, value, caption) => {
+            Some(("percent", icon, caption, value))
+        }
+        ui::ShowBoostedPercent(icon, value, _) => Some(("percent", icon, "".into(), value)),
+        ui::ShowBool(icon, label) => Some(("bool", icon, label, 0.0)),
+        ui::ShowText(icon, text) => Some(("text", icon, text, 0.0)),
+        ui::Hide => Some(("hide", "".into(), "".into(), 0.0)),
+        ui::Reload | ui::MonitorsChanged | ui::Quit => None,
+    }
+}
+
+/// Open a private connection to the bus at `address`, or to the one given by
+/// the environment.
+fn open(address: &Option<String>) -> Result<Channel, dbus::Error> {
+    match *address {
+        Some(ref address) => {
+            let mut channel = Channel::open_private(address)?;
+            channel.register()?;
+            Ok(channel)
+        }
+        None => Channel::get_private(BusType::Session),
+    }
+}
+
+/// Messages of all modules, and the descriptor that signals them
+struct Events {
+    receiver: Receiver<(&'static str, ui::Msg)>,
+    wake: Arc<EventFd>,
+    signal: Arc<Signal<()>>,
+}
+
+pub struct Subscription {
+    connection: Connection,
+    queue: Queue,
+    events: Option<Events>,
+}
+
+impl Reactive for Subscription {
+    type Params = Config;
+
+    fn start(config: Self::Params) -> Result<Self, String> {
+        let mut channel = open(&config.address)
+            .map_err(|e| format!("Cannot connect to the session bus: {}", e))?;
+        // Lets the reactor wait for the descriptor of the connection
+        channel.set_watch_enabled(true);
+        let connection = Connection::from(channel);
+        let reply = connection
+            .request_name(BUS_NAME, false, config.replace, true)
+            .map_err(|e| format!("Cannot request bus name `{}`: {}", BUS_NAME, e))?;
+        err_expect!(
+            reply == RequestNameReply::PrimaryOwner,
+            format!("Bus name `{}` is owned by another process", BUS_NAME)
+        );
+
+        let queue: Queue = Arc::new(Mutex::new(VecDeque::new()));
+        // Method calls are handled on the reactor thread, but the module is
+        // started on another one
+        let f = Factory::new_sync::<()>();
+        let event: Arc<Signal<()>> = Arc::new(
+            f.signal("Event", ())
+                .sarg::<&str, _>("module")
+                .sarg::<&str, _>("kind")
+                .sarg::<&str, _>("icon")
+                .sarg::<&str, _>("label")
+                .sarg::<f64, _>("value"),
+        );
+
+        let interface = f
+            .interface(INTERFACE, ())
+            .add_m(
+                f.method(
+                    "ShowPercent",
+                    (),
+                    show(&queue, |msg| {
+                        let (icon, label, value): (String, String, f64) = msg.read3()?;
+                        Ok(if label.is_empty() {
+                            ui::ShowPercent(icon.into(), value)
+                        } else {
+                            ui::ShowCaptionedPercent(icon.into(), value, label.into())
+                        })
+                    }),
+                ).inarg::<&str, _>("icon")
+                .inarg::<&str, _>("label")
+                .inarg::<f64, _>("value"),
+            ).add_m(
+                f.method(
+                    "ShowBool",
+                    (),
+                    show(&queue, |msg| {
+                        let (icon, label): (String, String) = msg.read2()?;
+                        Ok(ui::ShowBool(icon.into(), label.into()))
+                    }),
+                ).inarg::<&str, _>("icon")
+                .inarg::<&str, _>("label"),
+            ).add_m(
+                f.method(
+                    "ShowText",
+                    (),
+                    show(&queue, |msg| {
+                        let (icon, text): (String, String) = msg.read2()?;
+                        Ok(ui::ShowText(icon.into(), text.into()))
+                    }),
+                ).inarg::<&str, _>("icon")
+                .inarg::<&str, _>("text"),
+            ).add_m(f.method("Hide", (), show(&queue, |_| Ok(ui::Hide))))
+            .add_s(event.clone());
+        f.tree(())
+            .add(
+                f.object_path(OBJECT_PATH, ())
+                    .introspectable()
+                    .add(interface),
+            ).start_receive_send(&connection);
+        info!("Serving {} on the session bus", BUS_NAME);
+
+        let events = if config.events {
+            let wake = Arc::new(
+                EventFd::new().map_err(|e| format!("Cannot create eventfd: {}", e))?,
+            );
+            Some(Events {
+                receiver: subscribable::listen(wake.clone()),
+                wake,
+                signal: event,
+            })
+        } else {
+            None
+        };
+
+        Ok(Subscription {
+            connection,
+            queue,
+            events,
+        })
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        let wake = self.events.as_ref().map(|events| events.wake.as_raw_fd());
+        Some(self.connection.channel().watch().fd)
+            .into_iter()
+            .chain(wake)
+            .collect()
+    }
+
+    fn ready(&mut self, msgs: &mut Vec<ui::Msg>) -> Result<(), subscribable::Error> {
+        // Each call dispatches at most one message
+        while self
+            .connection
+            .process(Duration::from_secs(0))
+            .map_err(|e| {
+                subscribable::Error::new(format!("Lost connection to the session bus: {}", e), true)
+            })? {}
+
+        if let Some(ref events) = self.events {
+            events.wake.drain();
+            let path = Path::new(OBJECT_PATH).unwrap();
+            let interface = Interface::new(INTERFACE).unwrap();
+            for (module, msg) in events.receiver.try_iter() {
+                if let Some((kind, icon, label, value)) = event_args(msg) {
+                    let signal = events
+                        .signal
+                        .msg(&path, &interface)
+                        .append3(module, kind, &*icon)
+                        .append2(&*label, value);
+                    let _ = self.connection.send(signal);
+                }
+            }
+        }
+        self.connection.channel().flush();
+
+        msgs.extend(self.queue.lock().unwrap().drain(..));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        rc::Rc,
+        thread,
+        time::Instant,
+    };
+
+    use libc;
+
+    use self::dbus::blocking::LocalConnection;
+    use self::dbus::message::MatchRule;
+
+    use super::*;
+
+    /// A private session bus, which is stopped when dropped
+    struct Bus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl Bus {
+        fn start() -> Bus {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--print-address", "--nofork"].iter())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("Cannot start dbus-daemon");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            Bus {
+                daemon,
+                address: address.trim().to_string(),
+            }
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// Wait until any of `fds` is readable, like the reactor does.
+    fn wait(fds: &[RawFd]) {
+        let mut poll_fds: Vec<libc::pollfd> = fds
+            .iter()
+            .map(|&fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let n = unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, 5000) };
+        assert!(n > 0, "Timed out waiting for the module");
+    }
+
+    type EventArgs = (String, String, String, String, f64);
+
+    /// Call all methods of the interface like a client would, and return the
+    /// `Event` signals that were emitted in the meantime.
+    fn client(address: &str) -> Vec<EventArgs> {
+        let mut channel = Channel::open_private(address).unwrap();
+        channel.register().unwrap();
+        let connection = LocalConnection::from(channel);
+        let events = Rc::new(RefCell::new(Vec::new()));
+        connection
+            .add_match(MatchRule::new_signal(INTERFACE, "Event"), {
+                let events = events.clone();
+                move |args: EventArgs, _, _| {
+                    events.borrow_mut().push(args);
+                    true
+                }
+            })
+            .unwrap();
+
+        let proxy = connection.with_proxy(BUS_NAME, OBJECT_PATH, Duration::from_secs(5));
+        let () = proxy
+            .method_call(
+                INTERFACE,
+                "ShowPercent",
+                ("audio-volume-medium-symbolic", "", 0.4),
+            )
+            .unwrap();
+        let () = proxy
+            .method_call(INTERFACE, "ShowPercent", ("", "Keyboard backlight", 0.6))
+            .unwrap();
+        let () = proxy
+            .method_call(INTERFACE, "ShowBool", ("wifi-off", "Wi-Fi off"))
+            .unwrap();
+        let () = proxy
+            .method_call(INTERFACE, "ShowText", ("", "Profile: performance"))
+            .unwrap();
+        let () = proxy.method_call(INTERFACE, "Hide", ()).unwrap();
+
+        // Signals were sent before the replies, and wait to be dispatched
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while events.borrow().len() < 2 && Instant::now() < deadline {
+            connection.process(Duration::from_millis(100)).unwrap();
+        }
+        events.replace(Vec::new())
+    }
+
+    // Needs dbus-daemon; run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn queues_method_calls_and_emits_events() {
+        let bus = Bus::start();
+        let config = Config {
+            address: Some(bus.address.clone()),
+            ..Config::default()
+        };
+        let mut module = Subscription::start(config).ok().unwrap();
+        let client = {
+            let address = bus.address.clone();
+            thread::spawn(move || client(&address))
+        };
+
+        let mut msgs = Vec::new();
+        while msgs.is_empty() {
+            wait(&module.fds());
+            module.ready(&mut msgs).ok().unwrap();
+        }
+        // Once the client is connected, act as if other modules had shown
+        // these. Messages that do not show anything are not emitted.
+        subscribable::publish("alsa_volume", &ui::ShowPercent("volume-low".into(), 0.25));
+        subscribable::publish("ui", &ui::Reload);
+        subscribable::publish("rfkill", &ui::ShowBool("bluetooth".into(), "On".into()));
+        while msgs.len() < 5 {
+            wait(&module.fds());
+            module.ready(&mut msgs).ok().unwrap();
+        }
+
+        assert_eq!(
+            msgs,
+            vec![
+                ui::ShowPercent("audio-volume-medium-symbolic".into(), 0.4),
+                ui::ShowCaptionedPercent("".into(), 0.6, "Keyboard backlight".into()),
+                ui::ShowBool("wifi-off".into(), "Wi-Fi off".into()),
+                ui::ShowText("".into(), "Profile: performance".into()),
+                ui::Hide,
+            ]
+        );
+        let event = |module: &str, kind: &str, icon: &str, label: &str, value| {
+            (
+                module.to_string(),
+                kind.to_string(),
+                icon.to_string(),
+                label.to_string(),
+                value,
+            )
+        };
+        assert_eq!(
+            client.join().unwrap(),
+            vec![
+                event("alsa_volume", "percent", "volume-low", "", 0.25),
+                event("rfkill", "bool", "bluetooth", "On", 0.0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,234 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Modules choose icons by their meaning. Messages carry the name of the icon,
+// and the window renders it from the GTK icon theme as one of the symbolic
+// icons of the freedesktop naming specification, unless the configuration
+// overrides it with a glyph or an image.
+
+use std::borrow::Cow;
+
+/// An icon chosen by a module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Icon {
+    VolumeHigh,
+    VolumeMedium,
+    VolumeLow,
+    VolumeMuted,
+    MicrophoneHigh,
+    MicrophoneMedium,
+    MicrophoneLow,
+    MicrophoneMuted,
+    Brightness,
+    BrightnessLow,
+    BrightnessMedium,
+    BrightnessHigh,
+    Headphones,
+    Microphone,
+    LineOut,
+    Plug,
+    Wifi,
+    WifiOff,
+    Bluetooth,
+    BluetoothOff,
+    Cellular,
+    CellularOff,
+    Location,
+    LocationOff,
+    Radio,
+    RadioOff,
+}
+
+use self::Icon::*;
+
+impl Icon {
+    pub const ALL: &'static [Icon] = &[
+        VolumeHigh,
+        VolumeMedium,
+        VolumeLow,
+        VolumeMuted,
+        MicrophoneHigh,
+        MicrophoneMedium,
+        MicrophoneLow,
+        MicrophoneMuted,
+        Brightness,
+        BrightnessLow,
+        BrightnessMedium,
+        BrightnessHigh,
+        Headphones,
+        Microphone,
+        LineOut,
+        Plug,
+        Wifi,
+        WifiOff,
+        Bluetooth,
+        BluetoothOff,
+        Cellular,
+        CellularOff,
+        Location,
+        LocationOff,
+        Radio,
+        RadioOff,
+    ];
+
+    /// Name of the icon in messages and in the `[icons]` section of the
+    /// configuration
+    pub fn name(self) -> &'static str {
+        match self {
+            VolumeHigh => "volume-high",
+            VolumeMedium => "volume-medium",
+            VolumeLow => "volume-low",
+            VolumeMuted => "volume-muted",
+            MicrophoneHigh => "microphone-high",
+            MicrophoneMedium => "microphone-medium",
+            MicrophoneLow => "microphone-low",
+            MicrophoneMuted => "microphone-muted",
+            Brightness => "brightness",
+            BrightnessLow => "brightness-low",
+            BrightnessMedium => "brightness-medium",
+            BrightnessHigh => "brightness-high",
+            Headphones => "headphones",
+            Microphone => "microphone",
+            LineOut => "line-out",
+            Plug => "plug",
+            Wifi => "wifi",
+            WifiOff => "wifi-off",
+            Bluetooth => "bluetooth",
+            BluetoothOff => "bluetooth-off",
+            Cellular => "cellular",
+            CellularOff => "cellular-off",
+            Location => "location",
+            LocationOff => "location-off",
+            Radio => "radio",
+            RadioOff => "radio-off",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Icon> {
+        Icon::ALL.iter().cloned().find(|icon| icon.name() == name)
+    }
+
+    /// Speaker icon for a volume from 0.0 to 1.0, or above if boosted
+    pub fn volume(value: f64) -> Icon {
+        match level(value) {
+            0 => VolumeMuted,
+            1 => VolumeLow,
+            2 => VolumeMedium,
+            _ => VolumeHigh,
+        }
+    }
+
+    /// Like `volume`, for the sensitivity of a microphone
+    pub fn microphone(value: f64) -> Icon {
+        match level(value) {
+            0 => MicrophoneMuted,
+            1 => MicrophoneLow,
+            2 => MicrophoneMedium,
+            _ => MicrophoneHigh,
+        }
+    }
+
+    /// Like `volume`, for the brightness of a display, which is never off
+    pub fn brightness(value: f64) -> Icon {
+        match level(value) {
+            0 | 1 => BrightnessLow,
+            2 => BrightnessMedium,
+            _ => BrightnessHigh,
+        }
+    }
+
+    /// Names in the icon theme, in order of preference. Not every theme has
+    /// all icons of the naming specification, let alone the ones that are
+    /// only common extensions of it.
+    pub fn theme_names(self) -> &'static [&'static str] {
+        match self {
+            VolumeHigh => &["audio-volume-high-symbolic"],
+            VolumeMedium => &["audio-volume-medium-symbolic"],
+            VolumeLow => &["audio-volume-low-symbolic"],
+            VolumeMuted => &["audio-volume-muted-symbolic"],
+            MicrophoneHigh => &[
+                "microphone-sensitivity-high-symbolic",
+                "audio-input-microphone-symbolic",
+            ],
+            MicrophoneMedium => &[
+                "microphone-sensitivity-medium-symbolic",
+                "audio-input-microphone-symbolic",
+            ],
+            MicrophoneLow => &[
+                "microphone-sensitivity-low-symbolic",
+                "audio-input-microphone-symbolic",
+            ],
+            MicrophoneMuted => &[
+                "microphone-sensitivity-muted-symbolic",
+                "microphone-disabled-symbolic",
+            ],
+            Brightness => &["display-brightness-symbolic", "weather-clear-symbolic"],
+            BrightnessLow => &[
+                "display-brightness-low-symbolic",
+                "display-brightness-symbolic",
+            ],
+            BrightnessMedium => &[
+                "display-brightness-medium-symbolic",
+                "display-brightness-symbolic",
+            ],
+            BrightnessHigh => &[
+                "display-brightness-high-symbolic",
+                "display-brightness-symbolic",
+            ],
+            Headphones => &["audio-headphones-symbolic"],
+            Microphone => &["audio-input-microphone-symbolic"],
+            LineOut => &["audio-speakers-symbolic", "audio-card-symbolic"],
+            Plug => &["audio-card-symbolic"],
+            Wifi => &["network-wireless-symbolic"],
+            WifiOff => &[
+                "network-wireless-disabled-symbolic",
+                "network-wireless-offline-symbolic",
+            ],
+            Bluetooth => &["bluetooth-active-symbolic", "bluetooth-symbolic"],
+            BluetoothOff => &["bluetooth-disabled-symbolic"],
+            Cellular => &[
+                "network-cellular-signal-excellent-symbolic",
+                "network-cellular-symbolic",
+            ],
+            CellularOff => &[
+                "network-cellular-disabled-symbolic",
+                "network-cellular-offline-symbolic",
+            ],
+            Location => &["location-services-active-symbolic", "find-location-symbolic"],
+            LocationOff => &["location-services-disabled-symbolic"],
+            Radio => &["network-wireless-symbolic"],
+            RadioOff => &["airplane-mode-symbolic", "network-wireless-disabled-symbolic"],
+        }
+    }
+}
+
+/// 0 for a value of 0.0, then 1 to 3 for each third up to 1.0
+fn level(value: f64) -> u8 {
+    if value <= 0.0 {
+        0
+    } else if value < 1.0 / 3.0 {
+        1
+    } else if value < 2.0 / 3.0 {
+        2
+    } else {
+        3
+    }
+}
+
+impl From<Icon> for Cow<'static, str> {
+    fn from(icon: Icon) -> Self {
+        Cow::Borrowed(icon.name())
+    }
+}
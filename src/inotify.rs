@@ -0,0 +1,133 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Minimal wrapper around inotify(7), just enough to watch directories for
+// files being created, deleted or changed.
+
+use std::{
+    ffi::{CString, OsString},
+    io,
+    mem,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        io::{AsRawFd, RawFd},
+    },
+    path::Path,
+    ptr,
+};
+
+use libc;
+
+pub use libc::{IN_ATTRIB, IN_CLOSE_WRITE, IN_CREATE, IN_DELETE, IN_MOVED_TO};
+
+pub struct Event {
+    pub wd: i32,
+    pub mask: u32,
+    /// Name of the file within the watched directory, if any
+    pub name: Option<OsString>,
+}
+
+/// A non-blocking inotify instance. Wait for events by polling its file
+/// descriptor.
+pub struct Inotify {
+    fd: RawFd,
+}
+
+impl Inotify {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Inotify { fd })
+    }
+
+    /// Watch `path` for the events in `mask`, returning the watch descriptor.
+    pub fn add_watch(&self, path: &Path, mask: u32) -> io::Result<i32> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(wd)
+    }
+
+    /// Read all pending events without blocking.
+    pub fn read_events(&self) -> io::Result<Vec<Event>> {
+        let header = mem::size_of::<libc::inotify_event>();
+        let mut events = Vec::new();
+        // Large enough for at least one event with a maximum length name
+        let mut buffer = [0u8; 4096];
+
+        loop {
+            let n = unsafe {
+                libc::read(
+                    self.fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::WouldBlock => Ok(events),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => Err(e),
+                };
+            }
+
+            let n = n as usize;
+            let mut offset = 0;
+            while offset + header <= n {
+                let event: libc::inotify_event =
+                    unsafe { ptr::read_unaligned(buffer[offset..].as_ptr() as *const _) };
+                let name_start = offset + header;
+                let name_end = (name_start + event.len as usize).min(n);
+                // The name is padded with NUL bytes
+                let name: Vec<u8> = buffer[name_start..name_end]
+                    .iter()
+                    .cloned()
+                    .take_while(|&b| b != 0)
+                    .collect();
+
+                events.push(Event {
+                    wd: event.wd,
+                    mask: event.mask,
+                    name: if name.is_empty() {
+                        None
+                    } else {
+                        Some(OsString::from_vec(name))
+                    },
+                });
+                offset = name_end;
+            }
+        }
+    }
+}
+
+impl AsRawFd for Inotify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
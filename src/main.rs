@@ -23,10 +23,14 @@ extern crate stderrlog;
 
 // For Config
 #[macro_use]
+extern crate serde;
+#[macro_use]
 extern crate serde_derive;
+extern crate toml;
 
 // For UI
 extern crate gdk;
+extern crate gdk_pixbuf;
 extern crate glib;
 extern crate gtk;
 
@@ -34,42 +38,80 @@ extern crate gtk;
 extern crate threlm;
 use threlm::Threlm;
 
-// Currently only used by x11_backlight, though through ui::Window::connect
-extern crate gdk_sys;
-
-// Currently only used by alsa
+// For the reactor, signals and several modules
 extern crate libc;
 
+#[cfg(feature = "control_socket")]
+#[macro_use]
+extern crate serde_json;
+
 // Common macros
 #[macro_use]
 mod macros;
 
 // Common modules
 mod config;
+mod icons;
+mod inotify;
+mod modules;
+mod reactor;
+mod signals;
+#[cfg(feature = "x11_struts")]
+mod struts;
 mod subscribable;
+mod supervisor;
 mod ui;
+mod validate;
 
-use config::Config;
+use std::{env, path::PathBuf, process};
 
 // MODULES
+// Modules must also be listed in the registry in modules.rs
 #[cfg(feature = "alsa_volume")]
 mod alsa_volume;
+#[cfg(feature = "control_socket")]
+mod control_socket;
+#[cfg(feature = "dbus_service")]
+mod dbus_service;
+#[cfg(feature = "notifications")]
+mod notifications;
+#[cfg(feature = "pulse_volume")]
+mod pulse_volume;
+#[cfg(feature = "rfkill")]
+mod rfkill;
+#[cfg(feature = "sysfs_backlight")]
+mod sysfs_backlight;
 #[cfg(feature = "x11_backlight")]
 mod x11_backlight;
 
-// error if no modules were selected (this is the default)
-#[cfg(not(any(feature = "alsa_volume", feature = "x11_backlight")))]
-compile_error!("You should select some modules that you want to use. See the README.md for more information on how to do that.");
-
-lazy_static! {
-    static ref CONFIG: Config = config::read();
-}
-
 fn main() {
     stderrlog::new().module(module_path!()).init().unwrap();
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(i) = args.iter().position(|arg| arg == "--check-config") {
+        // Optionally followed by the file to check, rather than another option
+        let path = args.get(i + 1).filter(|arg| !arg.starts_with("--"));
+        process::exit(config::check_file(path.map(PathBuf::from)));
+    }
+
+    let config = config::read();
+
+    if args.iter().any(|arg| arg == "--diagnose") {
+        modules::diagnose(&config);
+        return;
+    }
+
+    // Before any threads are spawned, so that they all leave the signals to
+    // the reactor
+    signals::block(&signals::HANDLED);
+
     gtk::init().expect("Failed to initialise GTK.");
 
-    let _app = Threlm::new(ui::Window::new(&CONFIG));
+    let app = Threlm::new(ui::Window::new(config));
 
     gtk::main();
+
+    let status = reactor::shutdown();
+    drop(app);
+    process::exit(status);
 }
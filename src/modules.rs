@@ -0,0 +1,335 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+use serde::de::DeserializeOwned;
+use threlm::Actor;
+use toml;
+
+use config::{Config, Restart};
+#[allow(unused_imports)]
+use reactor::Reactive;
+#[allow(unused_imports)]
+use subscribable::Subscribable;
+use ui;
+use validate;
+use validate::Report;
+
+/// Everything modules may need from the UI to subscribe.
+pub struct Context<'a> {
+    pub actor: Actor<ui::Window>,
+    pub restart: &'a Restart,
+}
+
+/// A module that has been compiled into this binary. Whether it is actually
+/// run is decided at runtime by the `[modules]` section of the configuration.
+pub struct Module {
+    pub name: &'static str,
+    /// Whether the module runs if the configuration does not list the enabled
+    /// modules.
+    pub default: bool,
+    subscribe: fn(&Context, Option<&toml::Value>) -> Result<(), String>,
+    check: fn(&mut Report, &str, &toml::Value),
+}
+
+impl Module {
+    /// Spawn the module. `section` is the module's configuration section
+    /// `[modules.<name>]`, if there is one.
+    pub fn subscribe(&self, context: &Context, section: Option<&toml::Value>) -> Result<(), String> {
+        (self.subscribe)(context, section)
+    }
+
+    /// Check the module's configuration section at `key` without spawning
+    /// the module.
+    pub fn check(&self, report: &mut Report, key: &str, section: &toml::Value) {
+        (self.check)(report, key, section)
+    }
+}
+
+/// Deserialize a module's configuration section, falling back to the module's
+/// defaults if the section is missing.
+#[allow(dead_code)]
+fn section<T>(section: Option<&toml::Value>) -> Result<T, String>
+where
+    T: DeserializeOwned + Default,
+{
+    match section {
+        Some(section) => section
+            .clone()
+            .try_into()
+            .map_err(|e| format!("Invalid configuration section: {}", e)),
+        None => Ok(T::default()),
+    }
+}
+
+/// Check a module's configuration section, see `validate::table`.
+#[allow(dead_code)]
+fn check_section<T: DeserializeOwned>(report: &mut Report, key: &str, section: &toml::Value) {
+    validate::table::<T>(report, key, section);
+}
+
+/// List all modules that have been compiled into this binary.
+///
+/// To register a new module, add an entry below. The name must be the same as
+/// the name of the module's cargo feature. Modules that wait on file
+/// descriptors should implement `reactor::Reactive`; others implement the
+/// blocking `subscribable::Subscribable` and get a thread of their own.
+pub fn registry() -> Vec<Module> {
+    #[allow(unused_mut)]
+    let mut modules: Vec<Module> = Vec::new();
+
+    #[cfg(feature = "alsa_volume")]
+    modules.push(Module {
+        name: "alsa_volume",
+        default: true,
+        subscribe: |context, config| {
+            ::alsa_volume::Subscription::subscribe(
+                context.actor.clone(),
+                "alsa_volume",
+                section(config)?,
+                context.restart.clone(),
+            );
+            Ok(())
+        },
+        check: check_section::<::alsa_volume::Config>,
+    });
+
+    #[cfg(feature = "x11_backlight")]
+    modules.push(Module {
+        name: "x11_backlight",
+        default: true,
+        subscribe: |context, config| {
+            ::x11_backlight::Subscription::subscribe(
+                context.actor.clone(),
+                "x11_backlight",
+                section(config)?,
+                context.restart.clone(),
+            );
+            Ok(())
+        },
+        check: check_section::<::x11_backlight::Config>,
+    });
+
+    #[cfg(feature = "sysfs_backlight")]
+    modules.push(Module {
+        name: "sysfs_backlight",
+        // Would duplicate x11_backlight on most machines
+        default: false,
+        subscribe: |context, config| {
+            ::sysfs_backlight::Subscription::subscribe(
+                context.actor.clone(),
+                "sysfs_backlight",
+                section(config)?,
+                context.restart.clone(),
+            );
+            Ok(())
+        },
+        check: check_section::<::sysfs_backlight::Config>,
+    });
+
+    #[cfg(feature = "rfkill")]
+    modules.push(Module {
+        name: "rfkill",
+        default: true,
+        subscribe: |context, config| {
+            ::rfkill::Subscription::subscribe(
+                context.actor.clone(),
+                "rfkill",
+                section(config)?,
+                context.restart.clone(),
+            );
+            Ok(())
+        },
+        check: check_section::<::rfkill::Config>,
+    });
+
+    #[cfg(feature = "pulse_volume")]
+    modules.push(Module {
+        name: "pulse_volume",
+        // Would duplicate alsa_volume on most machines
+        default: false,
+        subscribe: |context, config| {
+            ::pulse_volume::Subscription::subscribe(
+                context.actor.clone(),
+                "pulse_volume",
+                section(config)?,
+                context.restart.clone(),
+            );
+            Ok(())
+        },
+        check: check_section::<::pulse_volume::Config>,
+    });
+
+    #[cfg(feature = "control_socket")]
+    modules.push(Module {
+        name: "control_socket",
+        default: true,
+        subscribe: |context, config| {
+            ::control_socket::Subscription::subscribe(
+                context.actor.clone(),
+                "control_socket",
+                section(config)?,
+                context.restart.clone(),
+            );
+            Ok(())
+        },
+        check: check_section::<::control_socket::Config>,
+    });
+
+    #[cfg(feature = "dbus_service")]
+    modules.push(Module {
+        name: "dbus_service",
+        default: true,
+        subscribe: |context, config| {
+            ::dbus_service::Subscription::subscribe(
+                context.actor.clone(),
+                "dbus_service",
+                section(config)?,
+                context.restart.clone(),
+            );
+            Ok(())
+        },
+        check: check_section::<::dbus_service::Config>,
+    });
+
+    #[cfg(feature = "notifications")]
+    modules.push(Module {
+        name: "notifications",
+        // Would conflict with the notification daemon of most desktops
+        default: false,
+        subscribe: |context, config| {
+            ::notifications::Subscription::subscribe(
+                context.actor.clone(),
+                "notifications",
+                section(config)?,
+                context.restart.clone(),
+            );
+            Ok(())
+        },
+        check: check_section::<::notifications::Config>,
+    });
+
+    modules
+}
+
+/// Names of the modules that run if the configuration does not list the
+/// enabled modules.
+pub fn default_names() -> Vec<String> {
+    registry()
+        .iter()
+        .filter(|m| m.default)
+        .map(|m| m.name.to_string())
+        .collect()
+}
+
+/// Subscribe to every module that is enabled in `config`. Modules that cannot
+/// be started are logged and skipped.
+pub fn subscribe_enabled(config: &Config, actor: Actor<ui::Window>) {
+    ::reactor::start(actor.clone());
+    let registry = registry();
+    let context = Context {
+        actor,
+        restart: &config.restart,
+    };
+
+    for name in &config.modules.enabled {
+        if !registry.iter().any(|m| name == m.name) {
+            warn!(
+                "Module `{}` is enabled in the configuration, but has not been compiled into this binary; ignoring it.",
+                name
+            );
+        }
+    }
+
+    for module in registry.iter().filter(|m| enabled(config, m.name)) {
+        info!("Subscribing to module `{}`", module.name);
+        let section = config.modules.sections.get(module.name);
+        if let Err(msg) = module.subscribe(&context, section) {
+            error!("Skipping module `{}`:\n  {}", module.name, msg);
+        }
+    }
+}
+
+fn enabled(config: &Config, name: &str) -> bool {
+    config.modules.enabled.iter().any(|enabled| enabled == name)
+}
+
+/// Check the `[modules]` section and the sections of all modules in it.
+pub fn check(report: &mut Report, modules: &toml::Value) {
+    let registry = registry();
+    let sections = match modules.as_table() {
+        Some(sections) => sections,
+        None => return,
+    };
+    let mut known = vec!["enabled"];
+    known.extend(registry.iter().map(|m| m.name));
+
+    for (name, section) in sections.iter().filter(|&(name, _)| name != "enabled") {
+        match registry.iter().find(|m| m.name == name) {
+            Some(module) => module.check(report, &format!("modules.{}", name), section),
+            // Sections of modules that have not been compiled in are not
+            // reported, since the default configuration has all of them.
+            None if section.is_table() => {}
+            None => report.push(validate::unknown_key("modules", name, &known)),
+        }
+    }
+}
+
+/// Apply a reloaded configuration to the modules: stop those that are no
+/// longer enabled, and subscribe again to those that are newly enabled or
+/// whose section changed. A changed restart policy applies to all modules.
+pub fn resubscribe(old: &Config, new: &Config, actor: Actor<ui::Window>) {
+    let context = Context {
+        actor,
+        restart: &new.restart,
+    };
+    let restart_changed = old.restart != new.restart;
+
+    for module in registry() {
+        let was_enabled = enabled(old, module.name);
+        if !enabled(new, module.name) {
+            if was_enabled {
+                info!("Stopping module `{}`", module.name);
+                ::reactor::stop(module.name);
+            }
+            continue;
+        }
+
+        let section = new.modules.sections.get(module.name);
+        if was_enabled && !restart_changed && old.modules.sections.get(module.name) == section {
+            continue;
+        }
+        info!("Subscribing to module `{}`", module.name);
+        if let Err(msg) = module.subscribe(&context, section) {
+            error!("Skipping module `{}`:\n  {}", module.name, msg);
+            ::reactor::stop(module.name);
+        }
+    }
+}
+
+/// Print diagnostics of the modules that support them, for `--diagnose`.
+/// Modules are diagnosed whether or not they are enabled.
+#[allow(unused_variables)]
+pub fn diagnose(config: &Config) {
+    #[cfg(feature = "x11_backlight")]
+    {
+        println!("x11_backlight:");
+        let report = section(config.modules.sections.get("x11_backlight"))
+            .and_then(|config| ::x11_backlight::diagnose(&config));
+        match report {
+            Ok(report) => print!("{}", report),
+            Err(msg) => println!("{}", msg),
+        }
+    }
+}
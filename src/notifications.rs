@@ -0,0 +1,501 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// A minimal notification daemon as in the Desktop Notifications Specification
+// (https://specifications.freedesktop.org/notification-spec/). Notifications
+// with a `value` hint, as sent for volume or brightness changes, become scales;
+// all others become texts. Since perspektiv shows a single popup at a time,
+// every notification replaces the one before, and the expiration timeout is
+// replaced by the configured duration of the window. Messages of all modules are
+// watched to tell when the notification that is shown has been hidden or
+// replaced, i.e. has been closed.
+
+extern crate dbus;
+extern crate dbus_tree;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{mpsc::Receiver, Arc, Mutex},
+    time::Duration,
+};
+
+use self::dbus::arg::{PropMap, RefArg};
+use self::dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply;
+use self::dbus::blocking::Connection;
+use self::dbus::channel::{BusType, Channel, Sender};
+use self::dbus::strings::{Interface, Path};
+use self::dbus::Message;
+use self::dbus_tree::{Factory, MethodErr, Signal};
+
+use reactor::{EventFd, Reactive};
+use subscribable;
+use ui;
+
+pub const BUS_NAME: &str = "org.freedesktop.Notifications";
+pub const OBJECT_PATH: &str = "/org/freedesktop/Notifications";
+pub const INTERFACE: &str = "org.freedesktop.Notifications";
+
+/// Version of the specification that is implemented
+const SPEC_VERSION: &str = "1.2";
+/// Notifications with the same value of this hint replace each other.
+const SYNCHRONOUS_HINT: &str = "x-canonical-private-synchronous";
+/// Name of the module, as its own messages are published
+const MODULE_NAME: &str = "notifications";
+
+// Reasons for `NotificationClosed`
+/// The popup timed out, or shows something else now.
+const EXPIRED: u32 = 1;
+/// Another module hid the popup, e.g. at the request of the user.
+const DISMISSED: u32 = 2;
+/// Closed by a call to `CloseNotification`
+const CLOSED_BY_CALL: u32 = 3;
+
+/// Configuration section `[modules.notifications]`
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Take over the bus name if another notification daemon owns it.
+    pub replace: bool,
+}
+
+/// Notifications seen so far, and messages for the UI.
+#[derive(Default)]
+struct State {
+    last_id: u32,
+    /// The notification that is shown, unless it has been closed
+    shown: Option<u32>,
+    /// Ids of notifications by their synchronous hint
+    synchronous: HashMap<String, u32>,
+    pending: VecDeque<ui::Msg>,
+    /// Ids and reasons of closed notifications, to emit `NotificationClosed` for
+    closed: VecDeque<(u32, u32)>,
+}
+
+impl State {
+    /// Choose the id of a new notification: the one it replaces, that of the
+    /// last notification of its synchronous group, or a fresh one.
+    fn assign_id(&mut self, replaces_id: u32, synchronous: Option<&str>) -> u32 {
+        let previous = synchronous.and_then(|tag| self.synchronous.get(tag).cloned());
+        let id = if replaces_id != 0 {
+            replaces_id
+        } else if let Some(id) = previous {
+            id
+        } else {
+            // Ids are never 0
+            self.last_id = self.last_id.wrapping_add(1).max(1);
+            self.last_id
+        };
+        if let Some(tag) = synchronous {
+            self.synchronous.insert(tag.to_string(), id);
+        }
+        id
+    }
+
+    fn notify(&mut self, msg: &Message) -> Result<u32, MethodErr> {
+        let mut args = msg.iter_init();
+        let _app_name: &str = args.read()?;
+        let replaces_id: u32 = args.read()?;
+        let app_icon: &str = args.read()?;
+        let summary: &str = args.read()?;
+        let body: &str = args.read()?;
+        let _actions: Vec<&str> = args.read()?;
+        let hints: PropMap = args.read()?;
+
+        // A name in the icon theme or a file:// URI
+        let icon: ui::Text = app_icon.trim_start_matches("file://").to_string().into();
+        let synchronous = hints.get(SYNCHRONOUS_HINT).and_then(|hint| hint.0.as_str());
+        let id = self.assign_id(replaces_id, synchronous);
+
+        // Usually an integer from 0 to 100
+        let value = hints
+            .get("value")
+            .and_then(|hint| hint.0.as_i64().map(|v| v as f64).or_else(|| hint.0.as_f64()));
+        self.pending.push_back(match value {
+            Some(value) if summary.is_empty() => ui::ShowPercent(icon, value / 100.0),
+            Some(value) => {
+                ui::ShowCaptionedPercent(icon, value / 100.0, summary.to_string().into())
+            }
+            None if body.is_empty() => ui::ShowText(icon, summary.to_string().into()),
+            None => ui::ShowText(icon, format!("{}\n{}", summary, body).into()),
+        });
+        if let Some(previous) = self.shown {
+            if previous != id {
+                self.closed.push_back((previous, EXPIRED));
+            }
+        }
+        self.shown = Some(id);
+        Ok(id)
+    }
+
+    /// Close and hide a notification. Only the one that is shown still exists.
+    fn close(&mut self, id: u32) -> Result<(), MethodErr> {
+        if self.shown != Some(id) {
+            return Err(MethodErr::failed(&format!("No notification with id {}", id)));
+        }
+        self.synchronous.retain(|_, &mut other| other != id);
+        self.shown = None;
+        self.pending.push_back(ui::Hide);
+        self.closed.push_back((id, CLOSED_BY_CALL));
+        Ok(())
+    }
+
+    /// Close the notification that is shown if a message of another module, or
+    /// the timeout of the UI, hides or replaces it.
+    fn observe(&mut self, module_name: &str, msg: &ui::Msg) {
+        let reason = match *msg {
+            _ if module_name == MODULE_NAME => return,
+            ui::Reload | ui::MonitorsChanged | ui::Quit => return,
+            ui::Hide if module_name != "ui" => DISMISSED,
+            _ => EXPIRED,
+        };
+        if let Some(id) = self.shown.take() {
+            self.closed.push_back((id, reason));
+        }
+    }
+}
+
+pub struct Subscription {
+    connection: Connection,
+    state: Arc<Mutex<State>>,
+    closed: Arc<Signal<()>>,
+    /// Messages of all modules, and the descriptor that signals them
+    events: Receiver<(&'static str, ui::Msg)>,
+    wake: Arc<EventFd>,
+}
+
+impl Reactive for Subscription {
+    type Params = Config;
+
+    fn start(config: Self::Params) -> Result<Self, String> {
+        let mut channel = Channel::get_private(BusType::Session)
+            .map_err(|e| format!("Cannot connect to the session bus: {}", e))?;
+        // Lets the reactor wait for the descriptor of the connection
+        channel.set_watch_enabled(true);
+        let connection = Connection::from(channel);
+        let reply = connection
+            .request_name(BUS_NAME, false, config.replace, true)
+            .map_err(|e| format!("Cannot request bus name `{}`: {}", BUS_NAME, e))?;
+        err_expect!(
+            reply == RequestNameReply::PrimaryOwner,
+            format!(
+                "Bus name `{}` is owned by another notification daemon; set `replace` to take it over",
+                BUS_NAME
+            )
+        );
+
+        let state = Arc::new(Mutex::new(State::default()));
+        // Method calls are handled on the reactor thread, but the module is
+        // started on another one
+        let f = Factory::new_sync::<()>();
+        let closed: Arc<Signal<()>> = Arc::new(
+            f.signal("NotificationClosed", ())
+                .sarg::<u32, _>("id")
+                .sarg::<u32, _>("reason"),
+        );
+        // Never emitted, since actions are not supported
+        let action_invoked = f
+            .signal("ActionInvoked", ())
+            .sarg::<u32, _>("id")
+            .sarg::<&str, _>("action_key");
+
+        let notify = {
+            let state = state.clone();
+            f.method("Notify", (), move |m| {
+                let id = state.lock().unwrap().notify(m.msg)?;
+                Ok(vec![m.msg.method_return().append1(id)])
+            }).inarg::<&str, _>("app_name")
+            .inarg::<u32, _>("replaces_id")
+            .inarg::<&str, _>("app_icon")
+            .inarg::<&str, _>("summary")
+            .inarg::<&str, _>("body")
+            .inarg::<Vec<&str>, _>("actions")
+            .inarg::<PropMap, _>("hints")
+            .inarg::<i32, _>("expire_timeout")
+            .outarg::<u32, _>("id")
+        };
+        let close = {
+            let state = state.clone();
+            f.method("CloseNotification", (), move |m| {
+                let id: u32 = m.msg.read1()?;
+                state.lock().unwrap().close(id)?;
+                Ok(vec![m.msg.method_return()])
+            }).inarg::<u32, _>("id")
+        };
+        let capabilities = f
+            .method("GetCapabilities", (), |m| {
+                let capabilities = vec!["body", "icon-static", SYNCHRONOUS_HINT];
+                Ok(vec![m.msg.method_return().append1(capabilities)])
+            }).outarg::<Vec<&str>, _>("capabilities");
+        let information = f
+            .method("GetServerInformation", (), |m| {
+                Ok(vec![
+                    m.msg
+                        .method_return()
+                        .append2("perspektiv", "perspektiv")
+                        .append2(env!("CARGO_PKG_VERSION"), SPEC_VERSION),
+                ])
+            }).outarg::<&str, _>("name")
+            .outarg::<&str, _>("vendor")
+            .outarg::<&str, _>("version")
+            .outarg::<&str, _>("spec_version");
+
+        f.tree(())
+            .add(
+                f.object_path(OBJECT_PATH, ()).introspectable().add(
+                    f.interface(INTERFACE, ())
+                        .add_m(notify)
+                        .add_m(close)
+                        .add_m(capabilities)
+                        .add_m(information)
+                        .add_s(closed.clone())
+                        .add_s(action_invoked),
+                ),
+            ).start_receive_send(&connection);
+        info!("Serving {} on the session bus", BUS_NAME);
+
+        let wake = Arc::new(EventFd::new().map_err(|e| format!("Cannot create eventfd: {}", e))?);
+        Ok(Subscription {
+            connection,
+            state,
+            closed,
+            events: subscribable::listen(wake.clone()),
+            wake,
+        })
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.connection.channel().watch().fd, self.wake.as_raw_fd()]
+    }
+
+    fn ready(&mut self, msgs: &mut Vec<ui::Msg>) -> Result<(), subscribable::Error> {
+        // Messages of other modules were sent before the notifications that
+        // are about to be dispatched
+        self.wake.drain();
+        {
+            let mut state = self.state.lock().unwrap();
+            for (module_name, msg) in self.events.try_iter() {
+                state.observe(module_name, &msg);
+            }
+        }
+        // Each call dispatches at most one message
+        while self
+            .connection
+            .process(Duration::from_secs(0))
+            .map_err(|e| {
+                subscribable::Error::new(format!("Lost connection to the session bus: {}", e), true)
+            })? {}
+
+        let mut state = self.state.lock().unwrap();
+        let path = Path::new(OBJECT_PATH).unwrap();
+        let interface = Interface::new(INTERFACE).unwrap();
+        for (id, reason) in state.closed.drain(..) {
+            let _ = self
+                .connection
+                .send(self.closed.msg(&path, &interface).append2(id, reason));
+        }
+        self.connection.channel().flush();
+        msgs.extend(state.pending.drain(..));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use self::dbus::arg::Variant;
+
+    use super::*;
+
+    /// A call of `Notify`, as `notify-send` sends it
+    fn notification(replaces_id: u32, summary: &str, body: &str, hints: PropMap) -> Message {
+        let actions: Vec<&str> = Vec::new();
+        Message::new_method_call(BUS_NAME, OBJECT_PATH, INTERFACE, "Notify")
+            .unwrap()
+            .append3("notify-send", replaces_id, "audio-volume-medium-symbolic")
+            .append3(summary, body, actions)
+            .append2(hints, -1i32)
+    }
+
+    fn hints(value: Option<i32>, synchronous: Option<&str>) -> PropMap {
+        let mut hints = PropMap::new();
+        if let Some(value) = value {
+            hints.insert("value".to_string(), Variant(Box::new(value)));
+        }
+        if let Some(tag) = synchronous {
+            hints.insert(
+                SYNCHRONOUS_HINT.to_string(),
+                Variant(Box::new(tag.to_string())),
+            );
+        }
+        hints
+    }
+
+    #[test]
+    fn replaces_id_takes_precedence() {
+        let mut state = State::default();
+        assert_eq!(state.assign_id(0, Some("volume")), 1);
+        assert_eq!(state.assign_id(7, Some("volume")), 7);
+        assert_eq!(state.assign_id(9, None), 9);
+        // The group continues with the id that replaced it
+        assert_eq!(state.assign_id(0, Some("volume")), 7);
+    }
+
+    #[test]
+    fn reuses_id_of_synchronous_group() {
+        let mut state = State::default();
+        assert_eq!(state.assign_id(0, Some("volume")), 1);
+        assert_eq!(state.assign_id(0, None), 2);
+        assert_eq!(state.assign_id(0, Some("brightness")), 3);
+        assert_eq!(state.assign_id(0, Some("volume")), 1);
+        assert_eq!(state.assign_id(0, Some("brightness")), 3);
+    }
+
+    #[test]
+    fn ids_are_never_zero() {
+        let mut state = State {
+            last_id: u32::max_value(),
+            ..State::default()
+        };
+        assert_eq!(state.assign_id(0, None), 1);
+        assert_eq!(state.assign_id(0, None), 2);
+    }
+
+    #[test]
+    fn notify_queues_scales_and_texts() {
+        let mut state = State::default();
+        let id = state
+            .notify(&notification(0, "", "", hints(Some(40), Some("volume"))))
+            .unwrap();
+        assert_eq!(id, 1);
+        let id = state
+            .notify(&notification(
+                0,
+                "Volume",
+                "",
+                hints(Some(60), Some("volume")),
+            ))
+            .unwrap();
+        assert_eq!(id, 1);
+        let id = state
+            .notify(&notification(
+                0,
+                "Battery low",
+                "10% left",
+                hints(None, None),
+            ))
+            .unwrap();
+        assert_eq!(id, 2);
+
+        let icon = "audio-volume-medium-symbolic";
+        assert_eq!(
+            state.pending.drain(..).collect::<Vec<_>>(),
+            vec![
+                ui::ShowPercent(icon.into(), 0.4),
+                ui::ShowCaptionedPercent(icon.into(), 0.6, "Volume".into()),
+                ui::ShowText(icon.into(), "Battery low\n10% left".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn replaced_notifications_expire() {
+        let mut state = State::default();
+        let volume = state
+            .notify(&notification(0, "", "", hints(Some(40), Some("volume"))))
+            .unwrap();
+        // The same notification is updated, and is not closed
+        state
+            .notify(&notification(0, "", "", hints(Some(50), Some("volume"))))
+            .unwrap();
+        state
+            .notify(&notification(volume, "", "", hints(Some(60), None)))
+            .unwrap();
+        assert!(state.closed.is_empty());
+
+        let text = state
+            .notify(&notification(0, "Hello", "", hints(None, None)))
+            .unwrap();
+        assert_eq!(
+            state.closed.drain(..).collect::<Vec<_>>(),
+            vec![(volume, EXPIRED)]
+        );
+        state.observe(
+            "alsa_volume",
+            &ui::ShowPercent("audio-volume-low".into(), 0.2),
+        );
+        assert_eq!(
+            state.closed.drain(..).collect::<Vec<_>>(),
+            vec![(text, EXPIRED)]
+        );
+        assert_eq!(state.shown, None);
+    }
+
+    #[test]
+    fn hiding_the_popup_closes_the_notification() {
+        let mut state = State::default();
+        let id = state
+            .notify(&notification(0, "Hello", "", hints(None, None)))
+            .unwrap();
+        // Its own messages and those that show nothing keep it
+        state.observe(MODULE_NAME, &ui::ShowText("".into(), "Hello".into()));
+        state.observe("ui", &ui::Reload);
+        assert!(state.closed.is_empty());
+        state.observe("ui", &ui::Hide);
+        assert_eq!(state.closed.pop_front(), Some((id, EXPIRED)));
+
+        let id = state
+            .notify(&notification(0, "Hello", "", hints(None, None)))
+            .unwrap();
+        state.observe("control_socket", &ui::Hide);
+        assert_eq!(state.closed.pop_front(), Some((id, DISMISSED)));
+        state.observe("ui", &ui::Hide);
+        assert!(state.closed.is_empty());
+    }
+
+    #[test]
+    fn close_hides_only_the_shown_notification() {
+        let mut state = State::default();
+        let volume = state
+            .notify(&notification(0, "", "", hints(Some(40), Some("volume"))))
+            .unwrap();
+        let text = state
+            .notify(&notification(0, "Hello", "", hints(None, None)))
+            .unwrap();
+        state.pending.clear();
+        state.closed.clear();
+
+        // Closing notifications that no longer exist fails, and emits nothing
+        assert!(state.close(volume).is_err());
+        assert!(state.close(42).is_err());
+        assert!(state.pending.is_empty());
+        assert!(state.closed.is_empty());
+        state.close(text).unwrap();
+        assert_eq!(state.pending.pop_front(), Some(ui::Hide));
+        assert_eq!(state.closed.pop_front(), Some((text, CLOSED_BY_CALL)));
+        assert!(state.close(text).is_err());
+        assert!(state.pending.is_empty());
+        assert!(state.closed.is_empty());
+
+        // The closed notification no longer names the group
+        let volume = state
+            .notify(&notification(0, "", "", hints(Some(40), Some("volume"))))
+            .unwrap();
+        state.close(volume).unwrap();
+        let id = state
+            .notify(&notification(0, "", "", hints(Some(50), Some("volume"))))
+            .unwrap();
+        assert_ne!(id, volume);
+    }
+}
@@ -0,0 +1,436 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Works with PulseAudio as well as PipeWire's pulse server. Change events from
+// the server only carry the facility and index of what changed, so every event
+// for the watched sink triggers an introspection query. The callbacks of those
+// queries queue up messages, which the polling function hands out one by one
+// while iterating the PulseAudio main loop.
+
+extern crate libpulse_binding as pulse;
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    ops::Deref,
+    rc::{Rc, Weak},
+};
+
+use self::pulse::callbacks::ListResult;
+use self::pulse::context::introspect::{ServerInfo, SinkInfo};
+use self::pulse::context::subscribe::{subscription_masks, Facility, Operation};
+use self::pulse::context::{flags, Context, State};
+use self::pulse::mainloop::api::Mainloop as MainloopTrait;
+use self::pulse::mainloop::events::io::flags as io_flags;
+use self::pulse::mainloop::events::io::IoEvent;
+use self::pulse::mainloop::standard::{IterateResult, Mainloop};
+use self::pulse::volume::VOLUME_NORM;
+
+use icons::Icon;
+use subscribable;
+use subscribable::{Cancel, PollFn, Subscribable};
+use ui;
+
+/// Highest volume offered by pavucontrol (+11 dB), relative to the normal
+/// volume. Boosted volumes are shown on a scale up to this, or further if the
+/// volume is higher still.
+const BOOST_MAX: f64 = 1.53;
+
+/// Configuration section `[modules.pulse_volume]`
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Name of the sink to watch (see `pactl list short sinks`). If unset, the
+    /// default sink is watched, following any changes of the default sink.
+    pub sink: Option<String>,
+    /// Server to connect to. If unset, the default server is used.
+    pub server: Option<String>,
+}
+
+/// Last known state of the watched sink
+#[derive(Clone, Copy)]
+struct Sink {
+    index: u32,
+    volume: f64,
+    muted: bool,
+}
+
+#[derive(Default)]
+struct Shared {
+    sink: Option<Sink>,
+    messages: VecDeque<ui::Msg>,
+}
+
+/// Callback for sink introspection queries. Records the new state of the
+/// watched sink, queueing a message if `report` is set and the state changed.
+fn on_sink_info(
+    shared: Rc<RefCell<Shared>>,
+    report: bool,
+) -> impl FnMut(ListResult<&SinkInfo>) + 'static {
+    move |result: ListResult<&SinkInfo>| {
+        let info = match result {
+            ListResult::Item(info) => info,
+            _ => return,
+        };
+        let sink = Sink {
+            index: info.index,
+            volume: info.volume.avg().0 as f64 / VOLUME_NORM.0 as f64,
+            muted: info.mute,
+        };
+
+        let mut shared = shared.borrow_mut();
+        let previous = shared.sink;
+        shared.sink = Some(sink);
+
+        match previous {
+            Some(previous) if report && previous.index == sink.index => {
+                if sink.muted && !previous.muted {
+                    shared
+                        .messages
+                        .push_back(ui::ShowBool(Icon::VolumeMuted.into(), "Muted".into()));
+                } else if !sink.muted && (previous.muted || sink.volume != previous.volume) {
+                    shared.messages.push_back(if sink.volume > 1.0 {
+                        ui::ShowBoostedPercent(Icon::VolumeHigh.into(), sink.volume, BOOST_MAX)
+                    } else {
+                        ui::ShowPercent(Icon::volume(sink.volume).into(), sink.volume)
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Start watching the sink called `name`, without reporting its current state.
+fn watch_sink(context: &Context, shared: &Rc<RefCell<Shared>>, name: &str) {
+    context
+        .introspect()
+        .get_sink_info_by_name(name, on_sink_info(shared.clone(), false));
+}
+
+/// Start watching the current default sink.
+fn watch_default_sink(context: &Rc<RefCell<Context>>, shared: &Rc<RefCell<Shared>>) {
+    let weak_context: Weak<RefCell<Context>> = Rc::downgrade(context);
+    let shared = shared.clone();
+    context
+        .borrow()
+        .introspect()
+        .get_server_info(move |info: &ServerInfo| {
+            if let (Some(context), Some(ref name)) =
+                (weak_context.upgrade(), info.default_sink_name.clone())
+            {
+                watch_sink(&context.borrow(), &shared, name);
+            }
+        });
+}
+
+/// The context connected to the server, and the main loop driving it. The
+/// context refers to the main loop without keeping it alive, so they are torn
+/// down in this order: the context is disconnected and dropped, then the event
+/// waking up the main loop, and then the main loop.
+struct Connection {
+    context: Rc<RefCell<Context>>,
+    /// Wakes up the main loop once the module is cancelled
+    cancel_event: Option<IoEvent<<Mainloop as MainloopTrait>::MI>>,
+    mainloop: Rc<RefCell<Mainloop>>,
+}
+
+impl Connection {
+    fn iterate(&self) -> Result<(), subscribable::Error> {
+        match self.mainloop.borrow_mut().iterate(true) {
+            IterateResult::Success(_) => Ok(()),
+            IterateResult::Quit(_) | IterateResult::Err(_) => Err(subscribable::Error::new(
+                "PulseAudio main loop stopped",
+                true,
+            )),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.context.borrow_mut().disconnect();
+        // The fields are dropped after this, in the order they are declared
+    }
+}
+
+pub struct Subscription();
+impl Subscribable for Subscription {
+    type Params = Config;
+
+    fn poll_factory(config: Self::Params, cancel: Cancel) -> Result<Box<PollFn>, String> {
+        let mainloop = Rc::new(RefCell::new(
+            Mainloop::new().ok_or("Cannot create PulseAudio main loop")?,
+        ));
+        let context = Rc::new(RefCell::new(
+            Context::new(mainloop.borrow().deref(), "perspektiv")
+                .ok_or("Cannot create PulseAudio context")?,
+        ));
+        let mut connection = Connection {
+            context,
+            cancel_event: None,
+            mainloop,
+        };
+        let context = connection.context.clone();
+
+        context
+            .borrow_mut()
+            .connect(
+                config.server.as_ref().map(|s| s.as_str()),
+                flags::NOAUTOSPAWN,
+                None,
+            ).map_err(|e| format!("Cannot connect to PulseAudio server: {}", e))?;
+
+        // Wait for the connection to be established
+        loop {
+            if connection.iterate().is_err() {
+                return Err("PulseAudio main loop stopped while connecting".to_string());
+            }
+            match context.borrow().get_state() {
+                State::Ready => break,
+                State::Failed | State::Terminated => {
+                    return Err("Cannot connect to PulseAudio server".to_string())
+                }
+                _ => {}
+            }
+        }
+
+        let shared = Rc::new(RefCell::new(Shared::default()));
+        match config.sink {
+            Some(ref name) => watch_sink(&context.borrow(), &shared, name),
+            None => watch_default_sink(&context, &shared),
+        }
+
+        // The callback is owned by the context, so it must only hold a weak
+        // reference to it.
+        let weak_context = Rc::downgrade(&context);
+        let callback_shared = shared.clone();
+        context
+            .borrow_mut()
+            .set_subscribe_callback(Some(Box::new(
+                move |facility: Option<Facility>, operation: Option<Operation>, index: u32| {
+                    let context = match weak_context.upgrade() {
+                        Some(context) => context,
+                        None => return,
+                    };
+                    let shared = &callback_shared;
+                    let watched = shared.borrow().sink.map(|sink| sink.index);
+
+                    match (facility, operation) {
+                        (Some(Facility::Server), _) => {
+                            if config.sink.is_none() {
+                                watch_default_sink(&context, shared);
+                            }
+                        }
+                        (Some(Facility::Sink), Some(Operation::Changed)) => {
+                            if watched == Some(index) {
+                                context
+                                    .borrow()
+                                    .introspect()
+                                    .get_sink_info_by_index(index, on_sink_info(shared.clone(), true));
+                            }
+                        }
+                        (Some(Facility::Sink), Some(Operation::New)) => {
+                            // The configured sink may only appear after startup
+                            if let (None, Some(ref name)) = (watched, config.sink.as_ref()) {
+                                watch_sink(&context.borrow(), shared, name);
+                            }
+                        }
+                        (Some(Facility::Sink), Some(Operation::Removed)) => {
+                            if watched == Some(index) {
+                                shared.borrow_mut().sink = None;
+                            }
+                        }
+                        _ => {}
+                    }
+                },
+            )));
+        context.borrow_mut().subscribe(
+            subscription_masks::SINK | subscription_masks::SERVER,
+            |success| {
+                if !success {
+                    error!("Failed to subscribe to PulseAudio sink events");
+                }
+            },
+        );
+
+        connection.cancel_event = Some(
+            connection
+                .mainloop
+                .borrow_mut()
+                .new_io_event(cancel.fd(), io_flags::INPUT, Box::new(|_, _, _| {}))
+                .ok_or("Cannot watch for cancellation in the PulseAudio main loop")?,
+        );
+
+        Ok(Box::new(move || loop {
+            if let Some(msg) = shared.borrow_mut().messages.pop_front() {
+                return Ok(msg);
+            }
+            cancel.check()?;
+            connection.iterate()?;
+            match connection.context.borrow().get_state() {
+                State::Failed | State::Terminated => {
+                    return Err(subscribable::Error::new(
+                        "Lost connection to the PulseAudio server",
+                        true,
+                    ))
+                }
+                _ => {}
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        path::PathBuf,
+        process::{self, Child, Command, Stdio},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    /// A PulseAudio server of its own for a test, with a null sink called
+    /// `perspektiv_test`. It is killed when dropped.
+    struct Server {
+        server: Child,
+        dir: PathBuf,
+    }
+
+    impl Server {
+        fn start() -> Server {
+            let dir = env::temp_dir().join(format!("perspektiv-pulse-{}", process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            let socket = format!(
+                "module-native-protocol-unix socket={}/native",
+                dir.display()
+            );
+            let args = [
+                "-n",
+                "--daemonize=no",
+                "--exit-idle-time=-1",
+                "--use-pid-file=no",
+                "--disable-shm=yes",
+                "-L",
+                socket.as_str(),
+                "-L",
+                "module-null-sink sink_name=perspektiv_test",
+            ];
+            let server = Command::new("pulseaudio")
+                .args(args.iter())
+                .env("HOME", &dir)
+                .env("XDG_RUNTIME_DIR", &dir)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("Cannot start pulseaudio");
+            let server = Server { server, dir };
+
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !server.dir.join("native").exists() {
+                assert!(Instant::now() < deadline, "pulseaudio did not start");
+                thread::sleep(Duration::from_millis(50));
+            }
+            server
+        }
+
+        fn address(&self) -> String {
+            format!("unix:{}/native", self.dir.display())
+        }
+
+        fn config(&self) -> Config {
+            Config {
+                sink: None,
+                server: Some(self.address()),
+            }
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.server.kill();
+            let _ = self.server.wait();
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Change the server at `address` like a user would.
+    fn pactl(address: &str, args: &[&str]) {
+        let status = Command::new("pactl")
+            .args(["--server", address].iter())
+            .args(args)
+            .stdout(Stdio::null())
+            .status()
+            .expect("Cannot run pactl");
+        assert!(status.success(), "pactl {:?} failed", args);
+    }
+
+    // Needs pulseaudio and pactl; run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn reports_volume_mute_and_default_sink() {
+        let server = Server::start();
+        let cancel = Cancel::new().unwrap();
+        let mut poll = Subscription::poll_factory(server.config(), cancel.clone()).unwrap();
+
+        // Each change is made once the module had time to handle the one
+        // before, while the test polls for messages
+        let address = server.address();
+        thread::spawn(move || {
+            let pause = || thread::sleep(Duration::from_millis(500));
+            pause();
+            pactl(&address, &["set-sink-volume", "perspektiv_test", "50%"]);
+            pause();
+            pactl(&address, &["set-sink-mute", "perspektiv_test", "1"]);
+            pause();
+            pactl(
+                &address,
+                &[
+                    "load-module",
+                    "module-null-sink",
+                    "sink_name=perspektiv_other",
+                ],
+            );
+            pactl(&address, &["set-default-sink", "perspektiv_other"]);
+            pause();
+            // No longer watched
+            pactl(&address, &["set-sink-volume", "perspektiv_test", "25%"]);
+            pactl(&address, &["set-sink-volume", "perspektiv_other", "75%"]);
+            // Give up on messages that do not arrive
+            thread::sleep(Duration::from_secs(10));
+            cancel.cancel();
+        });
+
+        let mut msgs = Vec::new();
+        while msgs.len() < 3 {
+            match poll() {
+                Ok(msg) => msgs.push(msg),
+                Err(_) => break,
+            }
+        }
+        assert_eq!(
+            msgs,
+            vec![
+                ui::ShowPercent(Icon::VolumeMedium.into(), 0.5),
+                ui::ShowBool(Icon::VolumeMuted.into(), "Muted".into()),
+                ui::ShowPercent(Icon::VolumeHigh.into(), 0.75),
+            ]
+        );
+    }
+}
@@ -0,0 +1,896 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// All modules are driven by a single thread, the reactor. Modules that wait on
+// file descriptors implement `Reactive`: the reactor waits for the descriptors
+// of all of them with epoll(7), and calls `ready` on the module whose
+// descriptors became readable.
+//
+// Modules implementing the blocking `Subscribable` trait are run through an
+// adapter. Their factory and polling function keep a thread of their own, but
+// hand their results to the reactor through a channel and an eventfd, so that
+// all modules are supervised in the same way. Restarts of failed modules are
+// scheduled as timeouts of the event loop rather than by sleeping.
+//
+// Starting a module may block, e.g. while connecting to a server, so modules
+// are started on threads of their own, which hand them to the reactor the same
+// way. Modules are stopped by dropping them, which closes their resources, and
+// may block as well, so they are dropped on threads of their own too. A module
+// replacing another is only started once its predecessor has been dropped.
+// The adapter cancels the polling function of a blocking module through an
+// eventfd that the module waits for along with its own descriptors. On SIGINT
+// or SIGTERM, the reactor asks the UI to quit, and `main` then shuts down the
+// reactor with all modules. On SIGHUP, it asks the UI to reload the
+// configuration.
+//
+// Subscribing to a module that is already on the reactor replaces it, so that
+// modules can be restarted with a new configuration.
+
+use std::{
+    collections::BTreeSet,
+    io, mem,
+    os::unix::io::{AsRawFd, RawFd},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use libc;
+use threlm::Actor;
+
+use config::Restart;
+use signals;
+use signals::SignalFd;
+use subscribable;
+use subscribable::{Cancel, Failures, PollResult, Subscribable};
+use supervisor::Supervisor;
+use ui;
+
+/// A module that is driven by the reactor instead of a thread of its own.
+pub trait Reactive: Sized + Send {
+    type Params: Clone + Send + 'static;
+
+    /// Run the module on the reactor, sending its messages to `actor`. If the
+    /// module fails, it is started again following the restart `policy`.
+    fn subscribe(
+        actor: Actor<ui::Window>,
+        module_name: &'static str,
+        params: Self::Params,
+        policy: Restart,
+    ) where
+        Self: 'static,
+    {
+        spawn(actor, module_name, policy, move || {
+            let params = params.clone();
+            Box::new(move || Self::start(params).map(|module| Box::new(module) as Box<dyn Driven>))
+        });
+    }
+
+    /// Set up the module. This is called on a thread of its own, as it may
+    /// block, e.g. while connecting to a server. The module is then handed to
+    /// the reactor thread.
+    fn start(params: Self::Params) -> Result<Self, String>;
+
+    /// Descriptors to wait for. They are queried again after every call of
+    /// `ready`, so the set may change, e.g. as devices are added.
+    fn fds(&self) -> Vec<RawFd>;
+
+    /// Those of `fds` that are also waited for until they are writable, e.g.
+    /// while output is queued for them. Queried along with `fds`.
+    fn writable_fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+
+    /// Handle readiness of any of the descriptors, or an error condition on
+    /// them, without blocking. This is also called once after `start`, when
+    /// none may be ready. Messages for the UI are pushed to `msgs`, and are
+    /// delivered even if an error is returned.
+    fn ready(&mut self, msgs: &mut Vec<ui::Msg>) -> Result<(), subscribable::Error>;
+}
+
+/// Object-safe part of `Reactive`, as seen by the reactor.
+trait Driven: Send {
+    fn fds(&self) -> Vec<RawFd>;
+    fn writable_fds(&self) -> Vec<RawFd> {
+        Vec::new()
+    }
+    fn ready(&mut self, msgs: &mut Vec<ui::Msg>) -> Result<(), subscribable::Error>;
+}
+
+impl<M: Reactive> Driven for M {
+    fn fds(&self) -> Vec<RawFd> {
+        Reactive::fds(self)
+    }
+
+    fn writable_fds(&self) -> Vec<RawFd> {
+        Reactive::writable_fds(self)
+    }
+
+    fn ready(&mut self, msgs: &mut Vec<ui::Msg>) -> Result<(), subscribable::Error> {
+        Reactive::ready(self, msgs)
+    }
+}
+
+/// A non-blocking eventfd(2), to wake up whoever waits for it.
+pub struct EventFd {
+    fd: RawFd,
+}
+
+impl EventFd {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventFd { fd })
+    }
+
+    /// Make the descriptor readable.
+    pub fn notify(&self) {
+        let one: u64 = 1;
+        unsafe {
+            libc::write(
+                self.fd,
+                &one as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            );
+        }
+    }
+
+    /// Make the descriptor unreadable again.
+    pub fn drain(&self) {
+        let mut count: u64 = 0;
+        unsafe {
+            libc::read(
+                self.fd,
+                &mut count as *mut u64 as *mut libc::c_void,
+                mem::size_of::<u64>(),
+            );
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Level-triggered epoll instance, waiting for descriptors to become readable,
+/// or writable.
+struct Epoll {
+    fd: RawFd,
+}
+
+impl Epoll {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Epoll { fd })
+    }
+
+    /// Wait for `fd` to become readable, and writable as well if `writable`
+    /// is set, reporting it with `token`. Descriptors that are already
+    /// registered get the new token and events.
+    fn add(&self, fd: RawFd, token: u64, writable: bool) -> io::Result<()> {
+        let events = if writable {
+            libc::EPOLLIN | libc::EPOLLOUT
+        } else {
+            libc::EPOLLIN
+        };
+        let mut event = libc::epoll_event {
+            events: events as u32,
+            u64: token,
+        };
+        if unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_ADD, fd, &mut event) } == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::EEXIST) {
+            return Err(e);
+        }
+        if unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_MOD, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Stop waiting for `fd`. Closed descriptors are removed automatically, so
+    /// errors are ignored.
+    fn delete(&self, fd: RawFd) {
+        let mut event = libc::epoll_event { events: 0, u64: 0 };
+        unsafe {
+            libc::epoll_ctl(self.fd, libc::EPOLL_CTL_DEL, fd, &mut event);
+        }
+    }
+
+    /// Wait for at most `timeout`, or forever, and return the tokens of the
+    /// descriptors that are ready.
+    fn wait(&self, timeout: Option<Duration>) -> io::Result<Vec<u64>> {
+        let timeout = match timeout {
+            // Round up, so that deadlines have passed when the wait returns
+            Some(t) => (t.as_secs() * 1000 + u64::from((t.subsec_nanos() + 999_999) / 1_000_000))
+                .min(i32::max_value() as u64) as i32,
+            None => -1,
+        };
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 32];
+        let n = unsafe {
+            libc::epoll_wait(self.fd, events.as_mut_ptr(), events.len() as i32, timeout)
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(Vec::new());
+            }
+            return Err(e);
+        }
+        Ok(events[..n as usize].iter().map(|event| event.u64).collect())
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Token of the reactor's own eventfd
+const WAKE: u64 = u64::max_value();
+/// Token of the signalfd
+const SIGNAL: u64 = u64::max_value() - 1;
+
+/// Exit status of perspektiv, set when a signal asks it to quit
+static EXIT_STATUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Requests to the reactor thread
+enum Request {
+    Spawn(Spawn),
+    /// Stop the module with this name for good.
+    Stop(&'static str),
+    /// Stop all modules, and report back when done.
+    Shutdown(Sender<()>),
+}
+
+/// Starts a module, on a thread of its own
+type Starter = Box<dyn FnOnce() -> Result<Box<dyn Driven>, String> + Send>;
+
+/// A module handed to the reactor
+struct Spawn {
+    actor: Actor<ui::Window>,
+    module_name: &'static str,
+    policy: Restart,
+    /// Called on the reactor thread for every start of the module
+    start: Box<dyn FnMut() -> Starter + Send>,
+}
+
+/// A module that has been started for slot `slot`
+struct Started {
+    slot: usize,
+    result: Result<Box<dyn Driven>, String>,
+}
+
+/// What becomes of a module that a thread is starting
+#[derive(Clone, Copy, PartialEq)]
+enum Starting {
+    /// No thread is starting the module.
+    No,
+    /// The reactor takes over the module once it has started.
+    Wanted,
+    /// The module has been stopped meanwhile, and is dropped once it has
+    /// started.
+    Unwanted,
+    /// Like `Unwanted`, but the module has been replaced, and its replacement
+    /// is started after that.
+    Replaced,
+}
+
+/// A module on the reactor. It is either starting, has been started, is
+/// waiting to be restarted, or has been stopped for good.
+struct Slot {
+    spawn: Spawn,
+    supervisor: Supervisor,
+    failures: Failures,
+    module: Option<Box<dyn Driven>>,
+    fds: Vec<RawFd>,
+    restart_at: Option<Instant>,
+    /// Modules are started by one thread at a time, so that they do not
+    /// compete for their resources.
+    starting: Starting,
+    /// Thread dropping the previous module, which must be done before the
+    /// next one starts
+    stopping: Option<JoinHandle<()>>,
+}
+
+struct Reactor {
+    epoll: Epoll,
+    wake: Arc<EventFd>,
+    requests: Receiver<Request>,
+    started: Receiver<Started>,
+    /// For the threads starting modules
+    started_sender: Sender<Started>,
+    signals: SignalFd,
+    /// To ask the UI to quit
+    actor: Actor<ui::Window>,
+    slots: Vec<Slot>,
+}
+
+impl Reactor {
+    fn run(mut self) {
+        loop {
+            let now = Instant::now();
+            let timeout = self
+                .slots
+                .iter()
+                .filter_map(|slot| slot.restart_at)
+                .min()
+                .map(|at| if at > now { at - now } else { Duration::from_secs(0) });
+            let tokens = match self.epoll.wait(timeout) {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    error!("Reactor cannot wait for events; stopping all modules: {}", e);
+                    return;
+                }
+            };
+
+            // Several descriptors of a module may be ready at once
+            let mut ready = BTreeSet::new();
+            for token in tokens {
+                match token {
+                    WAKE => {
+                        self.wake.drain();
+                        self.take_started();
+                        if !self.accept() {
+                            return;
+                        }
+                    }
+                    SIGNAL => self.signal(),
+                    _ => {
+                        ready.insert(token as usize);
+                    }
+                }
+            }
+            for i in ready {
+                self.drive(i);
+            }
+
+            let now = Instant::now();
+            for i in 0..self.slots.len() {
+                if self.slots[i].restart_at.map_or(false, |at| at <= now) {
+                    self.slots[i].restart_at = None;
+                    self.start(i);
+                }
+            }
+        }
+    }
+
+    /// Handle requests, starting the modules that have been handed to the
+    /// reactor. Returns false if the reactor has been shut down.
+    fn accept(&mut self) -> bool {
+        while let Ok(request) = self.requests.try_recv() {
+            let spawn = match request {
+                Request::Spawn(spawn) => spawn,
+                Request::Stop(module_name) => {
+                    if let Some(i) = self.find(module_name) {
+                        self.stop(i);
+                        self.slots[i].restart_at = None;
+                        self.slots[i].supervisor.stopped();
+                    }
+                    continue;
+                }
+                Request::Shutdown(done) => {
+                    for i in 0..self.slots.len() {
+                        self.stop(i);
+                        self.slots[i].restart_at = None;
+                    }
+                    // Modules that are still starting are dropped by the
+                    // threads starting them, once they find the reactor gone
+                    for slot in &mut self.slots {
+                        if let Some(stopping) = slot.stopping.take() {
+                            let _ = stopping.join();
+                        }
+                    }
+                    let _ = done.send(());
+                    return false;
+                }
+            };
+            let supervisor = Supervisor::new(spawn.module_name, spawn.policy.clone());
+            let slot = Slot {
+                spawn,
+                supervisor,
+                failures: Failures::default(),
+                module: None,
+                fds: Vec::new(),
+                restart_at: None,
+                starting: Starting::No,
+                stopping: None,
+            };
+            // The module it replaces must release its resources first
+            let i = match self.find(slot.spawn.module_name) {
+                Some(i) => {
+                    self.stop(i);
+                    self.slots[i].supervisor.stopped();
+                    let stopping = self.slots[i].stopping.take();
+                    let starting = match self.slots[i].starting {
+                        Starting::No => Starting::No,
+                        _ => Starting::Replaced,
+                    };
+                    self.slots[i] = Slot {
+                        starting,
+                        stopping,
+                        ..slot
+                    };
+                    i
+                }
+                None => {
+                    self.slots.push(slot);
+                    self.slots.len() - 1
+                }
+            };
+            if self.slots[i].starting == Starting::No {
+                self.start(i);
+            }
+        }
+        true
+    }
+
+    fn find(&self, module_name: &str) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|slot| slot.spawn.module_name == module_name)
+    }
+
+    /// Handle pending signals. SIGHUP asks the UI to reload the configuration.
+    /// Other signals ask the UI to quit, which shuts down the reactor in turn.
+    /// If that does not happen before the next of them, exit right away.
+    fn signal(&mut self) {
+        loop {
+            let signal = match self.signals.read() {
+                Ok(Some(signal)) => signal,
+                Ok(None) => return,
+                Err(e) => {
+                    error!("Cannot read signal: {}", e);
+                    return;
+                }
+            };
+            if signal == signals::SIGHUP {
+                info!("Received SIGHUP; reloading the configuration");
+                let _ = self.actor.tell(ui::Reload);
+                continue;
+            }
+            let status = 128 + signal as usize;
+            if EXIT_STATUS.swap(status, Ordering::SeqCst) != 0 {
+                warn!("Received {} again; exiting immediately", signals::name(signal));
+                process::exit(status as i32);
+            }
+            info!("Received {}; shutting down", signals::name(signal));
+            if self.actor.tell(ui::Quit).is_err() {
+                process::exit(status as i32);
+            }
+        }
+    }
+
+    /// Start module `i` on a thread of its own, once its previous module has
+    /// been dropped. `take_started` takes over the module from there.
+    fn start(&mut self, i: usize) {
+        let slot = &mut self.slots[i];
+        let start = (slot.spawn.start)();
+        let stopping = slot.stopping.take();
+        let started = self.started_sender.clone();
+        let wake = self.wake.clone();
+
+        let spawned = thread::Builder::new()
+            .name(slot.spawn.module_name.to_string())
+            .spawn(move || {
+                if let Some(stopping) = stopping {
+                    let _ = stopping.join();
+                }
+                let result = start();
+                // Otherwise the reactor is gone, and the module is dropped here
+                if started.send(Started { slot: i, result }).is_ok() {
+                    wake.notify();
+                }
+            });
+        match spawned {
+            Ok(_) => slot.starting = Starting::Wanted,
+            Err(e) => {
+                error!(
+                    "Could not start module `{}`:\n  Cannot spawn thread: {}",
+                    slot.spawn.module_name, e
+                );
+                self.backoff(i);
+            }
+        }
+    }
+
+    /// Take over the modules that have started, and schedule restarts of
+    /// those that failed to.
+    fn take_started(&mut self) {
+        while let Ok(Started { slot: i, result }) = self.started.try_recv() {
+            let starting = mem::replace(&mut self.slots[i].starting, Starting::No);
+            if starting != Starting::Wanted {
+                if let Ok(module) = result {
+                    self.slots[i].stopping = dispose(self.slots[i].spawn.module_name, module);
+                }
+                if starting == Starting::Replaced {
+                    self.start(i);
+                }
+                continue;
+            }
+
+            match result {
+                Ok(module) => {
+                    let slot = &mut self.slots[i];
+                    slot.supervisor.running();
+                    slot.failures.reset();
+                    slot.module = Some(module);
+                    // Setting up may have read data along with the replies it
+                    // waited for, which would not make the descriptors
+                    // readable again. `drive` registers them afterwards.
+                    self.drive(i);
+                }
+                Err(msg) => {
+                    error!(
+                        "Could not start module `{}`:\n  {}",
+                        self.slots[i].spawn.module_name, msg
+                    );
+                    self.backoff(i);
+                    self.sync_fds(i);
+                }
+            }
+        }
+    }
+
+    /// Let module `i` handle its ready descriptors.
+    fn drive(&mut self, i: usize) {
+        let mut msgs = Vec::new();
+        let result = match self.slots.get_mut(i).and_then(|slot| slot.module.as_mut()) {
+            Some(module) => module.ready(&mut msgs),
+            None => return,
+        };
+
+        for msg in msgs {
+            let slot = &mut self.slots[i];
+            slot.failures.reset();
+            slot.supervisor.recovered();
+            if !subscribable::deliver(&slot.spawn.actor, slot.spawn.module_name, msg) {
+                self.stop(i);
+                return;
+            }
+        }
+        if let Err(e) = result {
+            let slot = &mut self.slots[i];
+            if slot.failures.failed(slot.spawn.module_name, &e) {
+                self.stop(i);
+                self.backoff(i);
+                return;
+            }
+        }
+        self.sync_fds(i);
+    }
+
+    /// Stop waiting for the descriptors of module `i`, and drop it. A module
+    /// that is still starting is dropped once it has started.
+    fn stop(&mut self, i: usize) {
+        let slot = &mut self.slots[i];
+        for &fd in &slot.fds {
+            self.epoll.delete(fd);
+        }
+        slot.fds.clear();
+        if slot.starting == Starting::Wanted {
+            slot.starting = Starting::Unwanted;
+        }
+        if let Some(module) = slot.module.take() {
+            // Starting the module took over waiting for its predecessor
+            slot.stopping = dispose(slot.spawn.module_name, module);
+        }
+    }
+
+    /// Schedule a restart of module `i`, unless its restart budget is spent.
+    fn backoff(&mut self, i: usize) {
+        let slot = &mut self.slots[i];
+        slot.restart_at = slot.supervisor.schedule().map(|delay| Instant::now() + delay);
+    }
+
+    /// Wait for the descriptors that module `i` currently has.
+    fn sync_fds(&mut self, i: usize) {
+        let (fds, writable) = match self.slots[i].module {
+            Some(ref module) => (module.fds(), module.writable_fds()),
+            None => (Vec::new(), Vec::new()),
+        };
+        for &fd in &self.slots[i].fds {
+            if !fds.contains(&fd) {
+                self.epoll.delete(fd);
+            }
+        }
+        for &fd in &fds {
+            if let Err(e) = self.epoll.add(fd, i as u64, writable.contains(&fd)) {
+                error!(
+                    "Cannot wait for descriptor {} of module `{}`: {}",
+                    fd, self.slots[i].spawn.module_name, e
+                );
+            }
+        }
+        self.slots[i].fds = fds;
+    }
+}
+
+/// How to reach the reactor thread
+struct Handle {
+    requests: Sender<Request>,
+    wake: Arc<EventFd>,
+}
+
+lazy_static! {
+    static ref HANDLE: Mutex<Option<Handle>> = Mutex::new(None);
+}
+
+fn launch(actor: Actor<ui::Window>) -> io::Result<Handle> {
+    let epoll = Epoll::new()?;
+    let wake = Arc::new(EventFd::new()?);
+    epoll.add(wake.as_raw_fd(), WAKE, false)?;
+    let signals = SignalFd::new(&signals::HANDLED)?;
+    epoll.add(signals.as_raw_fd(), SIGNAL, false)?;
+
+    let (requests, receiver) = channel();
+    let (started_sender, started) = channel();
+    let reactor_wake = wake.clone();
+    thread::Builder::new()
+        .name("reactor".to_string())
+        .spawn(move || {
+            // Modules need not be `Send`, so they only exist on this thread
+            Reactor {
+                epoll,
+                wake: reactor_wake,
+                requests: receiver,
+                started,
+                started_sender,
+                signals,
+                actor,
+                slots: Vec::new(),
+            }.run()
+        })?;
+    Ok(Handle { requests, wake })
+}
+
+/// Start the reactor thread, which asks `actor` to quit on SIGINT and
+/// SIGTERM. Those signals must have been blocked with `signals::block`.
+pub fn start(actor: Actor<ui::Window>) {
+    let mut handle = HANDLE.lock().unwrap();
+    if handle.is_some() {
+        return;
+    }
+    match launch(actor) {
+        Ok(launched) => *handle = Some(launched),
+        Err(e) => {
+            error!("Cannot start reactor; no modules will run: {}", e);
+            // Nobody would handle them
+            signals::unblock(&signals::HANDLED);
+        }
+    }
+}
+
+/// Stop all modules, closing their resources, and the reactor. Returns the
+/// exit status for perspektiv: 128 plus the number of the signal that asked it
+/// to quit, or 0.
+pub fn shutdown() -> i32 {
+    if let Some(handle) = HANDLE.lock().unwrap().take() {
+        let (done, stopped) = channel();
+        if handle.requests.send(Request::Shutdown(done)).is_ok() {
+            handle.wake.notify();
+            if stopped.recv_timeout(Duration::from_secs(5)).is_err() {
+                warn!("Modules did not stop in time; exiting anyway");
+            }
+        }
+    }
+    EXIT_STATUS.load(Ordering::SeqCst) as i32
+}
+
+/// Hand a module to the reactor. `start` is called on the reactor thread for
+/// every start of the module, and returns the function starting it.
+fn spawn<F>(actor: Actor<ui::Window>, module_name: &'static str, policy: Restart, start: F)
+where
+    F: FnMut() -> Starter + Send + 'static,
+{
+    let spawn = Spawn {
+        actor,
+        module_name,
+        policy,
+        start: Box::new(start),
+    };
+    if let Err(msg) = request(Request::Spawn(spawn)) {
+        error!("{}; skipping module `{}`", msg, module_name);
+    }
+}
+
+/// Stop the module `module_name`, closing its resources, if it is on the
+/// reactor.
+pub fn stop(module_name: &'static str) {
+    if let Err(msg) = request(Request::Stop(module_name)) {
+        error!("{}; cannot stop module `{}`", msg, module_name);
+    }
+}
+
+fn request(request: Request) -> Result<(), &'static str> {
+    let handle = HANDLE.lock().unwrap();
+    let handle = match *handle {
+        Some(ref handle) => handle,
+        None => return Err("Reactor is not running"),
+    };
+    handle
+        .requests
+        .send(request)
+        .map_err(|_| "Reactor has stopped")?;
+    handle.wake.notify();
+    Ok(())
+}
+
+/// Drop a module on a thread of its own, as closing its resources may block,
+/// e.g. while the thread of a blocking module is cancelled.
+fn dispose(module_name: &'static str, module: Box<dyn Driven>) -> Option<JoinHandle<()>> {
+    thread::Builder::new()
+        .name(module_name.to_string())
+        .spawn(move || drop(module))
+        .map_err(|e| {
+            error!(
+                "Cannot spawn thread to stop module `{}`: {}",
+                module_name, e
+            )
+        })
+        .ok()
+}
+
+/// Adapter running the factory and the polling function of a blocking
+/// `Subscribable` in a thread of its own. Dropping it cancels the polling
+/// function.
+struct Blocking {
+    module_name: &'static str,
+    results: Receiver<PollResult>,
+    wake: Arc<EventFd>,
+    cancel: Cancel,
+    /// Disconnected once the thread has stopped
+    finished: Receiver<()>,
+}
+
+impl Blocking {
+    fn start<S: Subscribable>(
+        module_name: &'static str,
+        params: S::Params,
+    ) -> Result<Self, String> {
+        let wake = Arc::new(EventFd::new().map_err(|e| format!("Cannot create eventfd: {}", e))?);
+        let cancel = Cancel::new().map_err(|e| format!("Cannot create eventfd: {}", e))?;
+        let (sender, results) = channel();
+        let (finishing, finished) = channel::<()>();
+
+        let thread_wake = wake.clone();
+        let thread_cancel = cancel.clone();
+        thread::Builder::new()
+            .name(module_name.to_string())
+            .spawn(move || {
+                let _finishing = finishing;
+                // The factory may block, e.g. connecting to a bus, so it runs
+                // here rather than on the reactor thread. Failing to start is
+                // fatal like any error of the polling function.
+                let mut f = match S::poll_factory(params, thread_cancel) {
+                    Ok(f) => f,
+                    Err(msg) => {
+                        let msg = format!("Could not start the module: {}", msg);
+                        if sender.send(Err(subscribable::Error::new(msg, true))).is_ok() {
+                            thread_wake.notify();
+                        }
+                        return;
+                    }
+                };
+
+                // Stop under the same conditions as the reactor, so that a
+                // restarted module does not compete with its predecessor.
+                let mut failures = Failures::default();
+                loop {
+                    let result = f();
+                    let stop = match result {
+                        Ok(_) => {
+                            failures.reset();
+                            false
+                        }
+                        Err(ref e) => failures.stops(e),
+                    };
+                    if sender.send(result).is_err() {
+                        return; // the reactor dropped the module
+                    }
+                    thread_wake.notify();
+                    if stop {
+                        return;
+                    }
+                }
+            }).map_err(|e| format!("Cannot spawn thread: {}", e))?;
+
+        Ok(Blocking {
+            module_name,
+            results,
+            wake,
+            cancel,
+            finished,
+        })
+    }
+}
+
+impl Drop for Blocking {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        if let Err(RecvTimeoutError::Timeout) = self.finished.recv_timeout(Duration::from_secs(1)) {
+            warn!(
+                "Module `{}` did not stop when cancelled; abandoning its thread",
+                self.module_name
+            );
+        }
+    }
+}
+
+impl Driven for Blocking {
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.wake.as_raw_fd()]
+    }
+
+    fn ready(&mut self, msgs: &mut Vec<ui::Msg>) -> Result<(), subscribable::Error> {
+        self.wake.drain();
+        loop {
+            match self.results.try_recv() {
+                Ok(Ok(msg)) => msgs.push(msg),
+                Ok(Err(e)) => {
+                    // Come back for the results after the error
+                    self.wake.notify();
+                    return Err(e);
+                }
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => {
+                    return Err(subscribable::Error::new("Polling thread stopped", true))
+                }
+            }
+        }
+    }
+}
+
+/// Run a blocking module through the adapter.
+pub fn spawn_blocking<S: Subscribable + 'static>(
+    actor: Actor<ui::Window>,
+    module_name: &'static str,
+    params: S::Params,
+    policy: Restart,
+) {
+    spawn(actor, module_name, policy, move || {
+        let params = params.clone();
+        Box::new(move || {
+            Blocking::start::<S>(module_name, params)
+                .map(|module| Box::new(module) as Box<dyn Driven>)
+        })
+    });
+}
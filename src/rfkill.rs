@@ -0,0 +1,343 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Reading /dev/rfkill yields one `struct rfkill_event` (see linux/rfkill.h)
+// per read:
+//
+//     struct rfkill_event {
+//         __u32 idx;
+//         __u8  type;
+//         __u8  op;
+//         __u8  soft;
+//         __u8  hard;
+//     };
+//
+// Newer kernels may append further fields, which are ignored. Right after
+// opening the device, the kernel sends an `RFKILL_OP_ADD` event with the
+// current state of every rfkill device, followed by `RFKILL_OP_CHANGE` events
+// whenever a device is blocked or unblocked.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read},
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::{AsRawFd, RawFd},
+    },
+    path::PathBuf,
+};
+
+use libc;
+
+use icons::Icon;
+use reactor::Reactive;
+use subscribable;
+use ui;
+
+/// Size of the original `struct rfkill_event`.
+const EVENT_SIZE: usize = 8;
+
+/// Configuration section `[modules.rfkill]`
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Path of the rfkill character device.
+    pub device: PathBuf,
+    /// Device types to show popups for.
+    pub types: Vec<Type>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            device: PathBuf::from("/dev/rfkill"),
+            types: vec![Type::Wlan, Type::Bluetooth, Type::Wwan, Type::Uwb],
+        }
+    }
+}
+
+/// Type of an rfkill device, `enum rfkill_type`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Type {
+    Wlan,
+    Bluetooth,
+    Uwb,
+    Wimax,
+    Wwan,
+    Gps,
+    Fm,
+    Nfc,
+}
+
+impl Type {
+    fn from_raw(raw: u8) -> Option<Type> {
+        match raw {
+            1 => Some(Type::Wlan),
+            2 => Some(Type::Bluetooth),
+            3 => Some(Type::Uwb),
+            4 => Some(Type::Wimax),
+            5 => Some(Type::Wwan),
+            6 => Some(Type::Gps),
+            7 => Some(Type::Fm),
+            8 => Some(Type::Nfc),
+            _ => None, // 0 is RFKILL_TYPE_ALL, which is never reported for a device
+        }
+    }
+
+    fn icon(self, blocked: bool) -> Icon {
+        match (self, blocked) {
+            (Type::Wlan, false) => Icon::Wifi,
+            (Type::Wlan, true) => Icon::WifiOff,
+            (Type::Bluetooth, false) => Icon::Bluetooth,
+            (Type::Bluetooth, true) => Icon::BluetoothOff,
+            (Type::Wwan, false) | (Type::Wimax, false) => Icon::Cellular,
+            (Type::Wwan, true) | (Type::Wimax, true) => Icon::CellularOff,
+            (Type::Gps, false) => Icon::Location,
+            (Type::Gps, true) => Icon::LocationOff,
+            (Type::Uwb, false) | (Type::Fm, false) | (Type::Nfc, false) => Icon::Radio,
+            (Type::Uwb, true) | (Type::Fm, true) | (Type::Nfc, true) => Icon::RadioOff,
+        }
+    }
+
+    /// Labels for the unblocked, soft blocked and hard blocked states.
+    fn labels(self) -> [&'static str; 3] {
+        match self {
+            Type::Wlan => ["Wi-Fi on", "Wi-Fi off", "Wi-Fi disabled by hardware switch"],
+            Type::Bluetooth => [
+                "Bluetooth on",
+                "Bluetooth off",
+                "Bluetooth disabled by hardware switch",
+            ],
+            Type::Uwb => ["UWB on", "UWB off", "UWB disabled by hardware switch"],
+            Type::Wimax => ["WiMAX on", "WiMAX off", "WiMAX disabled by hardware switch"],
+            Type::Wwan => [
+                "Mobile broadband on",
+                "Mobile broadband off",
+                "Mobile broadband disabled by hardware switch",
+            ],
+            Type::Gps => ["GPS on", "GPS off", "GPS disabled by hardware switch"],
+            Type::Fm => ["FM radio on", "FM radio off", "FM radio disabled by hardware switch"],
+            Type::Nfc => ["NFC on", "NFC off", "NFC disabled by hardware switch"],
+        }
+    }
+}
+
+/// Operation of an rfkill event, `enum rfkill_operation`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Del,
+    Change,
+    ChangeAll,
+}
+
+/// Block state of an rfkill device. A hard block (hardware switch) takes
+/// precedence over a soft block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Unblocked,
+    SoftBlocked,
+    HardBlocked,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub idx: u32,
+    /// `None` if the kernel reports a type unknown to perspektiv
+    pub type_: Option<Type>,
+    pub op: Op,
+    pub state: State,
+}
+
+impl Event {
+    /// Parse a `struct rfkill_event` as read from /dev/rfkill. Returns `None`
+    /// if `bytes` is too short or the operation is unknown.
+    pub fn parse(bytes: &[u8]) -> Option<Event> {
+        if bytes.len() < EVENT_SIZE {
+            return None;
+        }
+
+        // idx is in native byte order
+        let idx = u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let op = match bytes[5] {
+            0 => Op::Add,
+            1 => Op::Del,
+            2 => Op::Change,
+            3 => Op::ChangeAll,
+            _ => return None,
+        };
+        let state = if bytes[7] != 0 {
+            State::HardBlocked
+        } else if bytes[6] != 0 {
+            State::SoftBlocked
+        } else {
+            State::Unblocked
+        };
+
+        Some(Event {
+            idx,
+            type_: Type::from_raw(bytes[4]),
+            op,
+            state,
+        })
+    }
+}
+
+pub struct Subscription {
+    config: Config,
+    device: File,
+    /// Last known state of each device, by index
+    states: HashMap<u32, State>,
+}
+
+impl Subscription {
+    /// Track the state of the device of `event`, and return the message to
+    /// show if it changed.
+    fn handle(&mut self, event: Event) -> Option<ui::Msg> {
+        match event.op {
+            Op::Add => {
+                self.states.insert(event.idx, event.state);
+            }
+            Op::Del => {
+                self.states.remove(&event.idx);
+            }
+            Op::Change => {
+                if self.states.insert(event.idx, event.state) == Some(event.state) {
+                    return None; // unchanged
+                }
+                let type_ = match event.type_ {
+                    Some(type_) if self.config.types.contains(&type_) => type_,
+                    _ => return None,
+                };
+                let label = type_.labels()[match event.state {
+                    State::Unblocked => 0,
+                    State::SoftBlocked => 1,
+                    State::HardBlocked => 2,
+                }];
+                let icon = type_.icon(event.state != State::Unblocked);
+                return Some(ui::ShowBool(icon.into(), label.into()));
+            }
+            Op::ChangeAll => {} // only ever written by userspace
+        }
+        None
+    }
+}
+
+impl Reactive for Subscription {
+    type Params = Config;
+
+    fn start(config: Self::Params) -> Result<Self, String> {
+        let device = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&config.device)
+            .map_err(|e| format!("Cannot open {:?}: {}", config.device, e))?;
+        Ok(Subscription {
+            config,
+            device,
+            states: HashMap::new(),
+        })
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.device.as_raw_fd()]
+    }
+
+    fn ready(&mut self, msgs: &mut Vec<ui::Msg>) -> Result<(), subscribable::Error> {
+        // Larger than EVENT_SIZE to also accept extended events
+        let mut buffer = [0u8; 32];
+        loop {
+            let n = match self.device.read(&mut buffer) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    return Err(subscribable::Error::new(
+                        format!("Cannot read rfkill event: {}", e),
+                        true,
+                    ))
+                }
+            };
+            let event = Event::parse(&buffer[..n]).ok_or_else(|| {
+                subscribable::Error::from(format!("Got malformed rfkill event: {:?}", &buffer[..n]))
+            })?;
+            msgs.extend(self.handle(event));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn parses_recorded_events() {
+        // Events as read from /dev/rfkill on x86_64: the original 8 bytes, and
+        // the 9 bytes of kernels since 5.11, which append `hard_block_reasons`
+        const WLAN_ADDED: [u8; 8] = [2, 0, 0, 0, 1, 0, 0, 0];
+        const BLUETOOTH_SOFT_BLOCKED: [u8; 9] = [3, 0, 0, 0, 2, 2, 1, 0, 0];
+        const WLAN_HARD_BLOCKED: [u8; 9] = [2, 0, 0, 0, 1, 2, 1, 1, 1];
+
+        assert_eq!(
+            Event::parse(&WLAN_ADDED),
+            Some(Event {
+                idx: 2,
+                type_: Some(Type::Wlan),
+                op: Op::Add,
+                state: State::Unblocked,
+            })
+        );
+        assert_eq!(
+            Event::parse(&BLUETOOTH_SOFT_BLOCKED),
+            Some(Event {
+                idx: 3,
+                type_: Some(Type::Bluetooth),
+                op: Op::Change,
+                state: State::SoftBlocked,
+            })
+        );
+        // A hard block takes precedence over the soft block
+        assert_eq!(
+            Event::parse(&WLAN_HARD_BLOCKED),
+            Some(Event {
+                idx: 2,
+                type_: Some(Type::Wlan),
+                op: Op::Change,
+                state: State::HardBlocked,
+            })
+        );
+    }
+
+    #[test]
+    fn parses_index_in_native_byte_order() {
+        let mut bytes = 0x0102_0304u32.to_ne_bytes().to_vec();
+        bytes.extend_from_slice(&[5, 3, 0, 0]);
+        let event = Event::parse(&bytes).unwrap();
+        assert_eq!(event.idx, 0x0102_0304);
+        assert_eq!(event.type_, Some(Type::Wwan));
+        assert_eq!(event.op, Op::ChangeAll);
+    }
+
+    #[test]
+    fn rejects_short_and_unknown_events() {
+        assert_eq!(Event::parse(&[2, 0, 0, 0, 1, 0, 0]), None);
+        assert_eq!(Event::parse(&[2, 0, 0, 0, 1, 4, 0, 0]), None);
+        // Types added by later kernels are passed on without a type
+        assert_eq!(Event::parse(&[2, 0, 0, 0, 42, 0, 0, 0]).unwrap().type_, None);
+    }
+}
@@ -0,0 +1,121 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Signals are handled synchronously by reading them from a signalfd(2). For
+// that, they must be blocked in every thread, so they are blocked before any
+// threads are spawned and every thread inherits the mask.
+
+use std::{
+    io, mem,
+    os::unix::io::{AsRawFd, RawFd},
+};
+
+use libc;
+
+pub use libc::{SIGHUP, SIGINT, SIGTERM};
+
+/// Signals that are handled by perspektiv
+pub const HANDLED: [i32; 3] = [SIGHUP, SIGINT, SIGTERM];
+
+fn sigset(signals: &[i32]) -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        for &signal in signals {
+            libc::sigaddset(&mut set, signal);
+        }
+        set
+    }
+}
+
+/// Block `signals` in the calling thread, and in threads spawned by it later.
+pub fn block(signals: &[i32]) {
+    let set = sigset(signals);
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, ::std::ptr::null_mut());
+    }
+}
+
+/// Restore the default handling of `signals` in the calling thread.
+pub fn unblock(signals: &[i32]) {
+    let set = sigset(signals);
+    unsafe {
+        libc::pthread_sigmask(libc::SIG_UNBLOCK, &set, ::std::ptr::null_mut());
+    }
+}
+
+/// A non-blocking signalfd for signals that have been blocked.
+pub struct SignalFd {
+    fd: RawFd,
+}
+
+impl SignalFd {
+    pub fn new(signals: &[i32]) -> io::Result<Self> {
+        let set = sigset(signals);
+        let fd = unsafe { libc::signalfd(-1, &set, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SignalFd { fd })
+    }
+
+    /// Read the next pending signal without blocking.
+    pub fn read(&self) -> io::Result<Option<i32>> {
+        let mut info: libc::signalfd_siginfo = unsafe { mem::zeroed() };
+        let size = mem::size_of::<libc::signalfd_siginfo>();
+        loop {
+            let n = unsafe {
+                libc::read(
+                    self.fd,
+                    &mut info as *mut libc::signalfd_siginfo as *mut libc::c_void,
+                    size,
+                )
+            };
+            if n == size as isize {
+                return Ok(Some(info.ssi_signo as i32));
+            }
+            let e = io::Error::last_os_error();
+            match e.kind() {
+                io::ErrorKind::WouldBlock => return Ok(None),
+                io::ErrorKind::Interrupted => continue,
+                _ => return Err(e),
+            }
+        }
+    }
+}
+
+impl AsRawFd for SignalFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// Name of a handled signal, for logging
+pub fn name(signal: i32) -> &'static str {
+    match signal {
+        SIGHUP => "SIGHUP",
+        SIGINT => "SIGINT",
+        SIGTERM => "SIGTERM",
+        _ => "signal",
+    }
+}
@@ -0,0 +1,440 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Panels reserve space at the edges of the screen with the EWMH properties
+// `_NET_WM_STRUT_PARTIAL` or `_NET_WM_STRUT`. Window managers take them into
+// account for `_NET_WORKAREA`, but not all of them set it (e.g. i3), and it
+// only describes a single rectangle for all monitors. Hence the struts are
+// read from the panels themselves.
+//
+// Struts are measured from the edges of the root window, not of the monitor
+// the panel is on, so a strut only shrinks a monitor if its edge lies within
+// that monitor.
+//
+// Reading the struts of all windows takes a round trip to the X server per
+// window, so they are cached. A thread of their own keeps them up to date with
+// the events of the X server, as windows come and go, change their struts, or
+// the root window is resized after a monitor has been plugged in.
+
+extern crate xcb;
+
+use std::{
+    collections::HashMap,
+    ops::Range,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use gdk;
+
+/// Space reserved by a panel, in pixels from each edge of the root window
+#[derive(Debug, PartialEq)]
+struct Strut {
+    left: u32,
+    right: u32,
+    top: u32,
+    bottom: u32,
+    /// Range along the edge that is reserved, for the left, right, top and
+    /// bottom edge
+    ranges: [Range<i32>; 4],
+}
+
+impl Strut {
+    /// Parse the value of `_NET_WM_STRUT_PARTIAL` (12 cardinals) or
+    /// `_NET_WM_STRUT` (4 cardinals, reserving the whole edge).
+    fn from_property(value: &[u32]) -> Option<Self> {
+        let range = |start: u32, end: u32| start as i32..end as i32 + 1;
+        let ranges = match value.len() {
+            12 => [
+                range(value[4], value[5]),
+                range(value[6], value[7]),
+                range(value[8], value[9]),
+                range(value[10], value[11]),
+            ],
+            4 => [
+                0..i32::max_value(),
+                0..i32::max_value(),
+                0..i32::max_value(),
+                0..i32::max_value(),
+            ],
+            _ => return None,
+        };
+        Some(Strut {
+            left: value[0],
+            right: value[1],
+            top: value[2],
+            bottom: value[3],
+            ranges,
+        })
+    }
+}
+
+fn overlaps(a: &Range<i32>, b: &Range<i32>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// The struts of all panels, and the size of the root window they are
+/// measured from
+struct Cache {
+    width: i32,
+    height: i32,
+    struts: HashMap<xcb::Window, Strut>,
+}
+
+impl Cache {
+    /// Shrink `area` of a monitor with the given GDK scale factor by the space
+    /// reserved by panels.
+    fn apply(&self, area: gdk::Rectangle, scale: i32) -> gdk::Rectangle {
+        // Struts are in device pixels
+        let (mut left, mut top) = (area.x * scale, area.y * scale);
+        let (mut right, mut bottom) = (left + area.width * scale, top + area.height * scale);
+        let (horizontal, vertical) = (left..right, top..bottom);
+
+        for strut in self.struts.values() {
+            let edges = [
+                strut.left as i32,
+                self.width - strut.right as i32,
+                strut.top as i32,
+                self.height - strut.bottom as i32,
+            ];
+            if strut.left > 0
+                && overlaps(&strut.ranges[0], &vertical)
+                && edges[0] > left
+                && edges[0] < right
+            {
+                left = edges[0];
+            }
+            if strut.right > 0
+                && overlaps(&strut.ranges[1], &vertical)
+                && edges[1] < right
+                && edges[1] > left
+            {
+                right = edges[1];
+            }
+            if strut.top > 0
+                && overlaps(&strut.ranges[2], &horizontal)
+                && edges[2] > top
+                && edges[2] < bottom
+            {
+                top = edges[2];
+            }
+            if strut.bottom > 0
+                && overlaps(&strut.ranges[3], &horizontal)
+                && edges[3] < bottom
+                && edges[3] > top
+            {
+                bottom = edges[3];
+            }
+        }
+
+        gdk::Rectangle {
+            x: left / scale,
+            y: top / scale,
+            width: (right - left) / scale,
+            height: (bottom - top) / scale,
+        }
+    }
+}
+
+/// A connection to the X server for reading the struts of all windows.
+struct Display {
+    connection: xcb::Connection,
+    root: xcb::Window,
+    client_list: xcb::Atom,
+    strut_partial: xcb::Atom,
+    strut: xcb::Atom,
+    /// Windows whose property changes are watched
+    windows: Vec<xcb::Window>,
+}
+
+impl Display {
+    /// Read a property of `window` that is a list of 32 bit values.
+    fn property(&self, window: xcb::Window, atom: xcb::Atom, type_: xcb::Atom) -> Vec<u32> {
+        xcb::get_property(&self.connection, false, window, atom, type_, 0, 1024)
+            .get_reply()
+            .ok()
+            .filter(|reply| reply.format() == 32)
+            .map_or_else(Vec::new, |reply| reply.value::<u32>().to_vec())
+    }
+
+    /// Windows that may be panels: those managed by the window manager, and
+    /// the children of the root window, which include unmanaged panels.
+    fn list_windows(&self) -> Vec<xcb::Window> {
+        let mut windows = self.property(self.root, self.client_list, xcb::ATOM_WINDOW);
+        if let Ok(tree) = xcb::query_tree(&self.connection, self.root).get_reply() {
+            windows.extend(tree.children());
+        }
+        windows.sort();
+        windows.dedup();
+        windows
+    }
+
+    /// Read the strut of `window` into `cache`.
+    fn read_strut(&self, window: xcb::Window, cache: &Mutex<Cache>) {
+        let partial = self.property(window, self.strut_partial, xcb::ATOM_CARDINAL);
+        let strut = if partial.is_empty() {
+            Strut::from_property(&self.property(window, self.strut, xcb::ATOM_CARDINAL))
+        } else {
+            Strut::from_property(&partial)
+        };
+        let mut cache = cache.lock().unwrap();
+        match strut {
+            Some(strut) => cache.struts.insert(window, strut),
+            None => cache.struts.remove(&window),
+        };
+    }
+
+    /// Watch the windows that may be panels now, reading the struts of those
+    /// that are new, and forget those that are gone.
+    fn update_windows(&mut self, cache: &Mutex<Cache>) {
+        let windows = self.list_windows();
+        for &window in &windows {
+            if self.windows.contains(&window) {
+                continue;
+            }
+            // Before reading the strut, so that no change is missed
+            xcb::change_window_attributes(
+                &self.connection,
+                window,
+                &[(xcb::CW_EVENT_MASK, xcb::EVENT_MASK_PROPERTY_CHANGE)],
+            );
+            self.read_strut(window, cache);
+        }
+        cache
+            .lock()
+            .unwrap()
+            .struts
+            .retain(|window, _| windows.contains(window));
+        self.windows = windows;
+    }
+
+    /// Keep `cache` up to date until the connection is lost.
+    fn run(mut self, cache: &Mutex<Cache>) {
+        while let Some(event) = self.connection.wait_for_event() {
+            // The most significant bit is set for events sent by clients
+            match event.response_type() & !0x80 {
+                xcb::PROPERTY_NOTIFY => {
+                    let event: &xcb::PropertyNotifyEvent = unsafe { xcb::cast_event(&event) };
+                    if event.window() == self.root {
+                        if event.atom() == self.client_list {
+                            self.update_windows(cache);
+                        }
+                    } else if (event.atom() == self.strut_partial || event.atom() == self.strut)
+                        && self.windows.contains(&event.window())
+                    {
+                        self.read_strut(event.window(), cache);
+                    }
+                }
+                // Children of the root window, which include unmanaged panels
+                xcb::CREATE_NOTIFY | xcb::DESTROY_NOTIFY => self.update_windows(cache),
+                xcb::CONFIGURE_NOTIFY => {
+                    let event: &xcb::ConfigureNotifyEvent = unsafe { xcb::cast_event(&event) };
+                    if event.window() == self.root {
+                        let mut cache = cache.lock().unwrap();
+                        cache.width = i32::from(event.width());
+                        cache.height = i32::from(event.height());
+                    }
+                }
+                _ => {}
+            }
+        }
+        warn!("Lost the connection to the X server; no longer updating the struts of panels");
+    }
+}
+
+/// The space reserved by panels, kept up to date on a thread of its own.
+pub struct Struts {
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl Struts {
+    pub fn connect() -> Result<Self, String> {
+        let (connection, screen) = xcb::Connection::connect(None)
+            .map_err(|e| format!("Cannot open display from $DISPLAY ({})", e))?;
+        let root = connection
+            .get_setup()
+            .roots()
+            .nth(screen as usize)
+            .map(|screen| screen.root())
+            .ok_or("Cannot get root window of the default screen")?;
+
+        let intern_atom = |name: &str| {
+            xcb::intern_atom(&connection, false, name)
+                .get_reply()
+                .map(|reply| reply.atom())
+                .map_err(|e| format!("Cannot get atom `{}` (error {})", name, e.error_code()))
+        };
+        let client_list = intern_atom("_NET_CLIENT_LIST")?;
+        let strut_partial = intern_atom("_NET_WM_STRUT_PARTIAL")?;
+        let strut = intern_atom("_NET_WM_STRUT")?;
+
+        // Changes of the client list and the size of the root window, and
+        // windows being created or destroyed
+        xcb::change_window_attributes(
+            &connection,
+            root,
+            &[(
+                xcb::CW_EVENT_MASK,
+                xcb::EVENT_MASK_PROPERTY_CHANGE
+                    | xcb::EVENT_MASK_STRUCTURE_NOTIFY
+                    | xcb::EVENT_MASK_SUBSTRUCTURE_NOTIFY,
+            )],
+        );
+        let geometry = xcb::get_geometry(&connection, root)
+            .get_reply()
+            .map_err(|e| format!("Cannot get size of the root window (error {})", e.error_code()))?;
+        let cache = Arc::new(Mutex::new(Cache {
+            width: i32::from(geometry.width()),
+            height: i32::from(geometry.height()),
+            struts: HashMap::new(),
+        }));
+
+        let mut display = Display {
+            connection,
+            root,
+            client_list,
+            strut_partial,
+            strut,
+            windows: Vec::new(),
+        };
+        display.update_windows(&cache);
+        thread::Builder::new()
+            .name("x11_struts".to_string())
+            .spawn({
+                let cache = cache.clone();
+                move || display.run(&cache)
+            }).map_err(|e| format!("Cannot start thread: {}", e))?;
+
+        Ok(Struts { cache })
+    }
+
+    /// Shrink `area` of a monitor with the given GDK scale factor by the space
+    /// reserved by panels.
+    pub fn apply(&self, area: gdk::Rectangle, scale: i32) -> gdk::Rectangle {
+        self.cache.lock().unwrap().apply(area, scale)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rectangle(x: i32, y: i32, width: i32, height: i32) -> gdk::Rectangle {
+        gdk::Rectangle {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Two monitors of 1920x1080 side by side
+    fn cache(struts: Vec<Vec<u32>>) -> Cache {
+        Cache {
+            width: 3840,
+            height: 1080,
+            struts: struts
+                .iter()
+                .enumerate()
+                .map(|(window, value)| {
+                    (window as xcb::Window, Strut::from_property(value).unwrap())
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn parses_partial_and_full_struts() {
+        assert_eq!(
+            Strut::from_property(&[0, 0, 30, 0, 0, 0, 0, 0, 0, 1919, 0, 0]),
+            Some(Strut {
+                left: 0,
+                right: 0,
+                top: 30,
+                bottom: 0,
+                ranges: [0..1, 0..1, 0..1920, 0..1],
+            })
+        );
+        assert_eq!(
+            Strut::from_property(&[0, 0, 0, 40]),
+            Some(Strut {
+                left: 0,
+                right: 0,
+                top: 0,
+                bottom: 40,
+                ranges: [
+                    0..i32::max_value(),
+                    0..i32::max_value(),
+                    0..i32::max_value(),
+                    0..i32::max_value(),
+                ],
+            })
+        );
+        assert_eq!(Strut::from_property(&[]), None);
+        assert_eq!(Strut::from_property(&[0, 0, 30]), None);
+    }
+
+    #[test]
+    fn struts_only_shrink_the_monitors_they_are_on() {
+        // A top panel on the left monitor, and a bottom panel on both
+        let cache = cache(vec![
+            vec![0, 0, 30, 0, 0, 0, 0, 0, 0, 1919, 0, 0],
+            vec![0, 0, 0, 40],
+        ]);
+        assert_eq!(
+            cache.apply(rectangle(0, 0, 1920, 1080), 1),
+            rectangle(0, 30, 1920, 1010)
+        );
+        assert_eq!(
+            cache.apply(rectangle(1920, 0, 1920, 1080), 1),
+            rectangle(1920, 0, 1920, 1040)
+        );
+    }
+
+    #[test]
+    fn struts_are_measured_from_the_root_window() {
+        // A panel at the right edge of the right monitor
+        let mut cache = cache(vec![vec![0, 50, 0, 0, 0, 0, 0, 1079, 0, 0, 0, 0]]);
+        assert_eq!(
+            cache.apply(rectangle(1920, 0, 1920, 1080), 1),
+            rectangle(1920, 0, 1870, 1080)
+        );
+        assert_eq!(
+            cache.apply(rectangle(0, 0, 1920, 1080), 1),
+            rectangle(0, 0, 1920, 1080)
+        );
+
+        // Once the right monitor has been unplugged, the panel moves to the
+        // left one
+        cache.width = 1920;
+        assert_eq!(
+            cache.apply(rectangle(0, 0, 1920, 1080), 1),
+            rectangle(0, 0, 1870, 1080)
+        );
+    }
+
+    #[test]
+    fn struts_are_in_device_pixels() {
+        let cache = Cache {
+            width: 3840,
+            height: 2160,
+            struts: cache(vec![vec![0, 0, 60, 0]]).struts,
+        };
+        assert_eq!(
+            cache.apply(rectangle(0, 0, 1920, 1080), 2),
+            rectangle(0, 30, 1920, 1050)
+        );
+    }
+}
@@ -13,8 +13,20 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 
-use std::thread;
+use std::{
+    io,
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
 
+use libc;
+
+use config::Restart;
+use reactor;
+use reactor::EventFd;
 use threlm;
 use ui;
 
@@ -45,71 +57,146 @@ impl<'a> From<&'a str> for Error {
     }
 }
 
+/// Lets the reactor stop a blocking module. Polling functions must wait for
+/// `fd` along with their own descriptors, and return the error of `check` once
+/// it is readable.
+#[derive(Clone)]
+pub struct Cancel(Arc<EventFd>);
+
+impl Cancel {
+    pub fn new() -> io::Result<Self> {
+        Ok(Cancel(Arc::new(EventFd::new()?)))
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+
+    pub fn cancel(&self) {
+        self.0.notify();
+    }
+
+    /// Fail with a fatal error if the module has been cancelled.
+    pub fn check(&self) -> Result<(), Error> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        if unsafe { libc::poll(&mut poll_fd, 1, 0) } > 0 {
+            return Err(Error::new("Cancelled", true));
+        }
+        Ok(())
+    }
+}
+
+/// Channel to a listener, and the descriptor to notify it with
+type Listener = (Sender<(&'static str, ui::Msg)>, Arc<EventFd>);
+
+lazy_static! {
+    static ref LISTENERS: Mutex<Vec<Listener>> = Mutex::new(Vec::new());
+}
+
+/// Receive a copy of every message that any module sends to the UI, along with
+/// the name of the module. `wake` is notified whenever a message is received,
+/// so that reactive modules can wait for it. Messages are not buffered for
+/// listeners that are dropped.
+pub fn listen(wake: Arc<EventFd>) -> Receiver<(&'static str, ui::Msg)> {
+    let (sender, receiver) = channel();
+    LISTENERS.lock().unwrap().push((sender, wake));
+    receiver
+}
+
+/// Send a copy of a message of a module to all listeners. `deliver` does so
+/// for every message that is sent to the UI.
+pub fn publish(module_name: &'static str, msg: &ui::Msg) {
+    LISTENERS.lock().unwrap().retain(|(listener, wake)| {
+        let sent = listener.send((module_name, msg.clone())).is_ok();
+        if sent {
+            wake.notify();
+        }
+        sent
+    });
+}
+
 // Type shortcuts
 pub type PollResult = Result<ui::Msg, Error>;
 pub type PollFn = dyn FnMut() -> PollResult;
 
 // Subscribable
 pub trait Subscribable {
-    type Params: Send + 'static;
+    type Params: Clone + Send + 'static;
 
+    /// Run the polling function in a new thread, sending its messages to
+    /// `actor` through the reactor. If the module fails, it is rebuilt by
+    /// calling `poll_factory` again, following the restart `policy`.
     fn subscribe(
         actor: threlm::Actor<ui::Window>,
         module_name: &'static str,
         params: Self::Params,
-    ) {
-        thread::Builder::new()
-            .name(module_name.to_string())
-            .spawn(move || {
-                let mut f = match Self::poll_factory(params) {
-                    Ok(f) => f,
-                    Err(msg) => {
-                        error!(
-                            "Could not create polling function for module `{}`:\n  {}",
-                            module_name, msg
-                        );
-                        return;
-                    }
-                };
-                let mut err_count: usize = 0;
-                loop {
-                    match f() {
-                        Ok(msg) => {
-                            err_count = 0;
-                            if actor.tell(msg).is_err() {
-                                error!("Terminating `{}` because the subscribing ui widget has been dropped.",
-                                         module_name);
-                                return;
-                            }
-                        }
-                        Err(e) => {
-                            let mut terminate = false;
-                            error!(
-                                "Module `{}` encountered an error:\n  {}\n  {}",
-                                module_name,
-                                e.message,
-                                if e.fatal {
-                                    terminate = true;
-                                    "This is a fatal error; terminating the module!"
-                                } else {
-                                    err_count += 1;
-                                    if err_count >= 3 {
-                                        terminate = true;
-                                        "This is the third non-fatal error in a row; terminating the module!"
-                                    } else {
-                                        "Attempting to continue execution of the module."
-                                    }
-                                }
-                            );
-                            if terminate {
-                                return;
-                            }
-                        }
-                    }
-                }
-            })
-            .unwrap();
+        policy: Restart,
+    ) where
+        Self: Sized + 'static,
+    {
+        reactor::spawn_blocking::<Self>(actor, module_name, params, policy);
     }
 
-    fn poll_factory(_params: Self::Params) -> Result<Box<PollFn>, String>;
+    /// Build the polling function, which must honour `cancel`.
+    fn poll_factory(_params: Self::Params, cancel: Cancel) -> Result<Box<PollFn>, String>;
+}
+
+/// Send a message of a module to the UI and to all listeners. Returns false if
+/// the module should be stopped because the subscribing ui widget has been
+/// dropped.
+pub fn deliver(actor: &threlm::Actor<ui::Window>, module_name: &'static str, msg: ui::Msg) -> bool {
+    publish(module_name, &msg);
+    if actor.tell(msg).is_err() {
+        error!(
+            "Terminating `{}` because the subscribing ui widget has been dropped.",
+            module_name
+        );
+        return false;
+    }
+    true
+}
+
+/// Counts the errors of a module since it last delivered a message.
+#[derive(Default)]
+pub struct Failures {
+    count: usize,
+}
+
+impl Failures {
+    /// The module delivered a message.
+    pub fn reset(&mut self) {
+        self.count = 0;
+    }
+
+    /// Whether the module must be stopped after error `e`: if it is fatal, or
+    /// the third non-fatal error in a row.
+    pub fn stops(&mut self, e: &Error) -> bool {
+        if e.fatal {
+            return true;
+        }
+        self.count += 1;
+        self.count >= 3
+    }
+
+    /// Like `stops`, but also logs the error.
+    pub fn failed(&mut self, module_name: &'static str, e: &Error) -> bool {
+        let stop = self.stops(e);
+        error!(
+            "Module `{}` encountered an error:\n  {}\n  {}",
+            module_name,
+            e.message,
+            if e.fatal {
+                "This is a fatal error; stopping the module!"
+            } else if stop {
+                "This is the third non-fatal error in a row; stopping the module!"
+            } else {
+                "Attempting to continue execution of the module."
+            }
+        );
+        stop
+    }
 }
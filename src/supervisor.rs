@@ -0,0 +1,237 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use config::Restart;
+
+/// Health of a supervised module.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Health {
+    /// The module is up and polling for events.
+    Running,
+    /// The module failed and will be restarted after `delay`. `restarts` is
+    /// the number of restarts since the module last recovered.
+    BackingOff { restarts: u32, delay: Duration },
+    /// The module failed too often and will not be restarted again.
+    GaveUp,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Health::Running => write!(f, "running"),
+            Health::BackingOff { restarts, delay } => write!(
+                f,
+                "backing off (restart {} in {}ms)",
+                restarts,
+                delay.as_secs() * 1000 + u64::from(delay.subsec_millis())
+            ),
+            Health::GaveUp => write!(f, "given up"),
+        }
+    }
+}
+
+lazy_static! {
+    static ref HEALTH: Mutex<BTreeMap<&'static str, Health>> = Mutex::new(BTreeMap::new());
+}
+
+/// Snapshot of the health of all supervised modules, by module name. Modules
+/// that have been stopped for good are not included.
+pub fn health() -> BTreeMap<&'static str, Health> {
+    HEALTH.lock().unwrap().clone()
+}
+
+fn set_health(module_name: &'static str, health: Health) {
+    HEALTH.lock().unwrap().insert(module_name, health);
+}
+
+/// Keeps track of restarts of a single module, implementing exponential
+/// backoff within the restart budget given by the configuration.
+pub struct Supervisor {
+    module_name: &'static str,
+    policy: Restart,
+    restarts: u32,
+    /// When the module was started, while it is running
+    running_since: Option<Instant>,
+}
+
+impl Supervisor {
+    pub fn new(module_name: &'static str, policy: Restart) -> Self {
+        Supervisor {
+            module_name,
+            policy,
+            restarts: 0,
+            running_since: None,
+        }
+    }
+
+    /// Mark the module as running.
+    pub fn running(&mut self) {
+        self.running_since = Some(Instant::now());
+        set_health(self.module_name, Health::Running);
+    }
+
+    /// The module has been stopped for good, or is about to be replaced by a
+    /// new one under the same name, which starts with a clean record.
+    pub fn stopped(&self) {
+        HEALTH.lock().unwrap().remove(self.module_name);
+    }
+
+    /// The module has delivered a message, so it has recovered from any
+    /// previous failures. This refills the restart budget.
+    pub fn recovered(&mut self) {
+        self.restarts = 0;
+    }
+
+    /// The module failed. Returns the delay after which it should be
+    /// restarted, or `None` if the restart budget is exhausted.
+    pub fn schedule(&mut self) -> Option<Duration> {
+        // Modules that fail now and then, e.g. on every suspend/resume, may
+        // not show anything in between
+        let reset_after = Duration::from_millis(self.policy.reset_after);
+        if self
+            .running_since
+            .take()
+            .map_or(false, |since| since.elapsed() >= reset_after)
+        {
+            self.recovered();
+        }
+
+        if self.restarts >= self.policy.max_restarts {
+            error!(
+                "Module `{}` failed {} times in a row; giving up on it.",
+                self.module_name, self.restarts
+            );
+            set_health(self.module_name, Health::GaveUp);
+            return None;
+        }
+
+        let delay = self.delay();
+        self.restarts += 1;
+        warn!(
+            "Restarting module `{}` in {}ms (restart {} of {}).",
+            self.module_name,
+            delay.as_secs() * 1000 + u64::from(delay.subsec_millis()),
+            self.restarts,
+            self.policy.max_restarts
+        );
+        set_health(
+            self.module_name,
+            Health::BackingOff {
+                restarts: self.restarts,
+                delay,
+            },
+        );
+
+        Some(delay)
+    }
+
+    /// Delay before the next restart: `initial_delay * multiplier^restarts`,
+    /// capped at `max_delay`.
+    fn delay(&self) -> Duration {
+        let delay = self.policy.initial_delay as f64
+            * self.policy.multiplier.max(1.0).powi(self.restarts as i32);
+        Duration::from_millis(delay.min(self.policy.max_delay as f64) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn supervisor(module_name: &'static str, max_delay: u64, max_restarts: u32) -> Supervisor {
+        Supervisor::new(
+            module_name,
+            Restart {
+                initial_delay: 500,
+                max_delay,
+                multiplier: 2.0,
+                max_restarts,
+                reset_after: 60_000,
+            },
+        )
+    }
+
+    fn delays(supervisor: &mut Supervisor, n: usize) -> Vec<Option<u64>> {
+        (0..n)
+            .map(|_| {
+                supervisor
+                    .schedule()
+                    .map(|delay| delay.as_secs() * 1000 + u64::from(delay.subsec_millis()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delay_grows_up_to_max_delay() {
+        let mut supervisor = supervisor("growing", 3000, 10);
+        assert_eq!(
+            delays(&mut supervisor, 5),
+            vec![Some(500), Some(1000), Some(2000), Some(3000), Some(3000)]
+        );
+        assert_eq!(
+            health()["growing"],
+            Health::BackingOff {
+                restarts: 5,
+                delay: Duration::from_millis(3000),
+            }
+        );
+    }
+
+    #[test]
+    fn gives_up_when_budget_is_spent() {
+        let mut supervisor = supervisor("failing", 60_000, 3);
+        assert_eq!(
+            delays(&mut supervisor, 4),
+            vec![Some(500), Some(1000), Some(2000), None]
+        );
+        assert_eq!(health()["failing"], Health::GaveUp);
+    }
+
+    #[test]
+    fn recovering_resets_budget_and_delay() {
+        let mut supervisor = supervisor("recovering", 60_000, 3);
+        assert_eq!(delays(&mut supervisor, 2), vec![Some(500), Some(1000)]);
+        supervisor.recovered();
+        assert_eq!(
+            delays(&mut supervisor, 4),
+            vec![Some(500), Some(1000), Some(2000), None]
+        );
+    }
+
+    #[test]
+    fn stable_uptime_resets_budget_and_delay() {
+        let mut supervisor = supervisor("resuming", 60_000, 3);
+        assert_eq!(delays(&mut supervisor, 2), vec![Some(500), Some(1000)]);
+
+        // Failing right after a restart does not count as recovering
+        supervisor.running();
+        assert_eq!(delays(&mut supervisor, 1), vec![Some(2000)]);
+
+        supervisor.running();
+        supervisor.running_since = Some(Instant::now() - Duration::from_secs(61));
+        assert_eq!(delays(&mut supervisor, 1), vec![Some(500)]);
+        assert_eq!(
+            health()["resuming"].to_string(),
+            "backing off (restart 1 in 500ms)"
+        );
+    }
+}
@@ -0,0 +1,307 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Backlight devices under /sys/class/backlight/<device>/ expose their current
+// brightness in `actual_brightness` and its upper bound in `max_brightness`.
+// Whenever the brightness changes, the kernel calls sysfs_notify on
+// `actual_brightness`, which wakes up poll(2) with POLLPRI. Not every driver
+// does so reliably, so the file is also re-read periodically.
+
+use std::{
+    fs,
+    fs::File,
+    io,
+    io::{Read, Seek, SeekFrom},
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use libc;
+
+use icons::Icon;
+use subscribable;
+use subscribable::{Cancel, PollFn, Subscribable};
+use ui;
+
+/// Configuration section `[modules.sysfs_backlight]`
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Directory containing the backlight devices.
+    pub root: PathBuf,
+    /// Name of the backlight device, e.g. `intel_backlight`. If unset, the
+    /// device is chosen by its type: firmware, then platform, then raw.
+    pub device: Option<String>,
+    /// Milliseconds between reads of the brightness for drivers that do not
+    /// notify about changes. 0 disables periodic reads.
+    pub poll_interval: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            root: PathBuf::from("/sys/class/backlight"),
+            device: None,
+            poll_interval: 500,
+        }
+    }
+}
+
+struct Backlight {
+    actual_brightness: File,
+    max_brightness: u64,
+    brightness: u64,
+}
+
+impl Backlight {
+    fn new(device: &Path) -> Result<Self, String> {
+        let max_brightness = read_value(&mut open(&device.join("max_brightness"))?)
+            .map_err(|e| format!("Cannot read `max_brightness` of {:?}: {}", device, e))?;
+        err_if!(
+            max_brightness == 0,
+            format!("Backlight device {:?} has a maximum brightness of 0", device)
+        );
+
+        let mut actual_brightness = open(&device.join("actual_brightness"))?;
+        let brightness = read_value(&mut actual_brightness)
+            .map_err(|e| format!("Cannot read `actual_brightness` of {:?}: {}", device, e))?;
+
+        Ok(Backlight {
+            actual_brightness,
+            max_brightness,
+            brightness,
+        })
+    }
+
+    /// Block until the brightness changes, `timeout` milliseconds have passed
+    /// or the module is cancelled. A negative timeout blocks indefinitely.
+    fn wait(&self, timeout: i32, cancel: &Cancel) -> io::Result<()> {
+        let mut poll_fds = [
+            libc::pollfd {
+                fd: self.actual_brightness.as_raw_fd(),
+                events: libc::POLLPRI,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: cancel.fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { libc::poll(poll_fds.as_mut_ptr(), 2, timeout) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-read the brightness, returning the new brightness in [0, 1] if it
+    /// changed.
+    fn update(&mut self) -> io::Result<Option<f64>> {
+        let brightness = read_value(&mut self.actual_brightness)?;
+        if brightness == self.brightness {
+            return Ok(None);
+        }
+        self.brightness = brightness;
+        Ok(Some(brightness as f64 / self.max_brightness as f64))
+    }
+}
+
+fn open(path: &Path) -> Result<File, String> {
+    File::open(path).map_err(|e| format!("Cannot open {:?}: {}", path, e))
+}
+
+/// Read an integer attribute from the start of a sysfs file. Reading also
+/// re-arms the file for the next sysfs_notify.
+fn read_value(file: &mut File) -> io::Result<u64> {
+    let mut buffer = String::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_string(&mut buffer)?;
+    buffer
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Find the device to watch: the configured one, or else the one preferred by
+/// its type in the same way as systemd-backlight and most desktops do.
+fn find_device(config: &Config) -> Result<PathBuf, String> {
+    if let Some(ref device) = config.device {
+        let path = config.root.join(device);
+        err_expect!(
+            path.join("actual_brightness").exists(),
+            format!("Backlight device {:?} does not exist", path)
+        );
+        return Ok(path);
+    }
+
+    let mut devices: Vec<(usize, PathBuf)> = fs::read_dir(&config.root)
+        .map_err(|e| format!("Cannot list backlight devices in {:?}: {}", config.root, e))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.join("actual_brightness").exists())
+        .map(|path| {
+            let mut kind = String::new();
+            let _ = File::open(path.join("type")).and_then(|mut f| f.read_to_string(&mut kind));
+            let priority = match kind.trim() {
+                "firmware" => 0,
+                "platform" => 1,
+                "raw" => 2,
+                _ => 3,
+            };
+            (priority, path)
+        }).collect();
+    devices.sort();
+
+    match devices.into_iter().next() {
+        Some((_, path)) => Ok(path),
+        None => Err(format!("Found no backlight devices in {:?}", config.root)),
+    }
+}
+
+pub struct Subscription();
+impl Subscribable for Subscription {
+    type Params = Config;
+
+    fn poll_factory(config: Self::Params, cancel: Cancel) -> Result<Box<PollFn>, String> {
+        let device = find_device(&config)?;
+        let mut backlight = Backlight::new(&device)?;
+        info!("Watching backlight device {:?}", device);
+
+        let timeout = if config.poll_interval == 0 {
+            -1
+        } else {
+            config.poll_interval as i32
+        };
+
+        Ok(Box::new(move || {
+            loop {
+                backlight
+                    .wait(timeout, &cancel)
+                    .map_err(|e| subscribable::Error::new(format!("poll failed: {}", e), true))?;
+                cancel.check()?;
+                match backlight.update() {
+                    Ok(Some(brightness)) => {
+                        return Ok(ui::ShowPercent(Icon::brightness(brightness).into(), brightness))
+                    }
+                    Ok(None) => {} // spurious wakeup or timeout; wait again
+                    Err(e) => {
+                        return Err(subscribable::Error::from(format!(
+                            "Cannot read brightness: {}",
+                            e
+                        )))
+                    }
+                }
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    /// A directory of backlight devices, removed when dropped
+    struct Root(PathBuf);
+
+    impl Root {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("perspektiv-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Root(path)
+        }
+
+        fn device(&self, name: &str, kind: &str, brightness: u64, max_brightness: u64) -> PathBuf {
+            let device = self.0.join(name);
+            fs::create_dir(&device).unwrap();
+            fs::write(device.join("type"), format!("{}\n", kind)).unwrap();
+            fs::write(device.join("max_brightness"), format!("{}\n", max_brightness)).unwrap();
+            set_brightness(&device, brightness);
+            device
+        }
+
+        fn config(&self, device: Option<&str>) -> Config {
+            Config {
+                root: self.0.clone(),
+                device: device.map(String::from),
+                poll_interval: 10,
+            }
+        }
+    }
+
+    impl Drop for Root {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn set_brightness(device: &Path, brightness: u64) {
+        fs::write(device.join("actual_brightness"), format!("{}\n", brightness)).unwrap();
+    }
+
+    #[test]
+    fn reports_fraction_of_max_brightness() {
+        let root = Root::new("sysfs-fraction");
+        let device = root.device("acpi_video0", "firmware", 600, 1200);
+        let cancel = Cancel::new().unwrap();
+        let mut poll = Subscription::poll_factory(root.config(None), cancel).unwrap();
+
+        set_brightness(&device, 300);
+        assert_eq!(
+            poll().ok(),
+            Some(ui::ShowPercent(Icon::brightness(0.25).into(), 0.25))
+        );
+        set_brightness(&device, 1200);
+        assert_eq!(
+            poll().ok(),
+            Some(ui::ShowPercent(Icon::BrightnessHigh.into(), 1.0))
+        );
+    }
+
+    #[test]
+    fn prefers_firmware_devices() {
+        let root = Root::new("sysfs-prefer");
+        root.device("intel_backlight", "raw", 10, 100);
+        let firmware = root.device("acpi_video0", "firmware", 10, 100);
+        root.device("dell_backlight", "platform", 10, 100);
+
+        assert_eq!(find_device(&root.config(None)), Ok(firmware));
+    }
+
+    #[test]
+    fn missing_device() {
+        let root = Root::new("sysfs-missing");
+        root.device("acpi_video0", "firmware", 10, 100);
+
+        // The message is followed by the location of the check
+        let error = find_device(&root.config(Some("intel_backlight"))).unwrap_err();
+        let expected = format!(
+            "Backlight device {:?} does not exist",
+            root.0.join("intel_backlight")
+        );
+        assert!(error.starts_with(&expected), "{}", error);
+
+        let empty = Root::new("sysfs-empty");
+        assert_eq!(
+            find_device(&empty.config(None)).unwrap_err(),
+            format!("Found no backlight devices in {:?}", empty.0)
+        );
+    }
+}
@@ -13,27 +13,41 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 
+use std::{borrow::Cow, path::Path};
+
 use gtk;
 use gtk::{
-    AdjustmentExt, Align::Center, ContainerExt, Continue, CssProviderExt, GtkWindowExt, Inhibit,
-    LabelExt, Orientation, PositionType::*, ScaleExt, WidgetExt, WindowType::Popup,
+    AdjustmentExt, Align::Center, BoxExt, ContainerExt, Continue, CssProviderExt, GtkWindowExt,
+    IconSize, IconThemeExt, ImageExt, Inhibit, Justification, LabelExt, Orientation,
+    PositionType::*, ScaleExt, StyleContextExt, WidgetExt, WindowType::Popup,
     STYLE_PROVIDER_PRIORITY_APPLICATION,
 };
 use threlm::{Actor, Model, View};
 
-use gdk::ScreenExt;
+use gdk;
+use gdk::{DeviceExt, DisplayExt, ScreenExt, SeatExt};
+use gdk_pixbuf::Pixbuf;
 
 use glib::source::{source_remove, SourceId};
-use glib::translate::{FromGlib, ToGlib, ToGlibPtr};
 
-use config::{Config, MarginHoriz, MarginVert};
-use subscribable::Subscribable;
+use config;
+use config::{Area, Config, IconOverride, MarginHoriz, MarginVert, Placement};
+use icons::Icon;
+use modules;
+use subscribable;
+#[cfg(feature = "x11_struts")]
+use struts::Struts;
 
 pub use self::Msg::*;
 
 pub struct Window {
-    config: &'static Config,
+    config: Config,
     timeout: Option<SourceId>,
+    /// Provider of the custom CSS file, if one is loaded
+    css: Option<gtk::CssProvider>,
+    /// Reads the space reserved by panels, unless X is unavailable
+    #[cfg(feature = "x11_struts")]
+    struts: Option<Struts>,
     widgets: Widgets,
 }
 
@@ -42,82 +56,58 @@ struct Widgets {
     gtk_window: gtk::Window,
     outer_container: gtk::Box,
     container: gtk::Box,
+    /// Icons of the theme and image files
+    icon_image: gtk::Image,
+    /// Icons given as text, e.g. glyphs of an icon font
     icon: gtk::Label,
     scale_adjustment: gtk::Adjustment,
     scale_widget: gtk::Scale,
+    /// Like the scale, but going beyond 100%
+    boost_adjustment: gtk::Adjustment,
+    boost_widget: gtk::Scale,
+    caption_label: gtk::Label,
     bool_label: gtk::Label,
+    text_label: gtk::Label,
 }
 
-/// Compute offset to $anchor from a dual-variant enum, where $opposite is the
-/// variant that is not $anchor. $offset is spaced $distance from $anchor.
+/// Compute offset to $anchor from an enum with the variants $anchor,
+/// $opposite and $center. $opposite is spaced $distance from $anchor, and
+/// $center halfway between them.
 ///
 /// For example, if anchored to a variant `Left`, an input of $distance = 1080,
 /// $value = Right(10) will yield 1070, which is the distance of Right(10) from
-/// the anchor Left(0).
+/// the anchor Left(0), and $value = Center(10) will yield 550.
 macro_rules! dimen {
-    ($anchor:path, $opposite:path, $distance:expr, $value:expr) => {
+    ($anchor:path, $opposite:path, $center:path, $distance:expr, $value:expr) => {
         match $value {
             $anchor(v) => v,
             $opposite(v) => $distance - v,
+            $center(v) => $distance / 2 + v,
         }
     };
 }
 
 impl Window {
-    pub fn new(config: &'static Config) -> Self {
+    pub fn new(config: Config) -> Self {
         let gtk_window = gtk::Window::new(Popup);
         gtk_window.set_name("window");
-
-        // Module x11_backlight requires a GDK window, so we create an invisible
-        // window and hide it immediately before continuing setup. The created
-        // GDK window will remain and be used whenever gtk_window.show() is
-        // called.
-        gtk_window.set_property_default_width(0);
-        gtk_window.set_property_default_height(0);
-        gtk_window.show(); // create GDK window
-        gtk_window.hide(); // and immediately hide
-
-        // Need the screen for some configuration
-        let screen = gtk_window
-            .get_screen()
-            .expect("Expected GTK window to have a GDK screen.");
-        let monitor = screen.get_primary_monitor();
-        let monitor_rect = screen.get_monitor_geometry(monitor);
-
-        // Actually set up the window
-        gtk_window.resize(config.window.width, config.window.height);
         gtk_window.set_resizable(false);
-        gtk_window.move_(
-            dimen!(
-                MarginHoriz::Left,
-                MarginHoriz::Right,
-                monitor_rect.width - config.window.width,
-                config.window.margin_horiz
-            ),
-            dimen!(
-                MarginVert::Top,
-                MarginVert::Bottom,
-                monitor_rect.height - config.window.height,
-                config.window.margin_vert
-            ),
-        );
-
-        if config.window.opacity < 100 {
-            gtk_window.set_opacity(config.window.opacity as f64 / 100.0);
-        }
 
         // Topmost container holding child widgets in the window
-        let outer_container = gtk::Box::new(Orientation::Vertical, config.window.spacing as i32);
+        let outer_container = gtk::Box::new(Orientation::Vertical, 0);
         outer_container.set_name("outer_container");
         outer_container.set_valign(Center);
-        outer_container.set_border_width(config.window.spacing);
         gtk_window.add(&outer_container);
 
+        let icon_image = gtk::Image::new();
+        icon_image.set_name("icon");
+        outer_container.add(&icon_image);
+
         let icon = gtk::Label::new(None);
         icon.set_name("icon");
         outer_container.add(&icon);
 
-        let container = gtk::Box::new(Orientation::Vertical, config.window.spacing as i32);
+        let container = gtk::Box::new(Orientation::Vertical, 0);
         container.set_name("container");
         outer_container.add(&container);
 
@@ -125,14 +115,32 @@ impl Window {
         let scale_widget = gtk::Scale::new(Orientation::Horizontal, &scale_adjustment);
         scale_widget.set_name("percentage");
         scale_widget.set_digits(0);
-        scale_widget.set_draw_value(config.percentage.show_numeric);
         scale_widget.set_value_pos(Bottom);
         container.add(&scale_widget);
 
+        let boost_adjustment = gtk::Adjustment::new(0.0, 0.0, 101.0, 1.0, 5.0, 1.0);
+        let boost_widget = gtk::Scale::new(Orientation::Horizontal, &boost_adjustment);
+        boost_widget.set_name("boosted_percentage");
+        boost_widget.set_digits(0);
+        boost_widget.set_value_pos(Bottom);
+        // Where boosting begins
+        boost_widget.add_mark(100.0, Top, None);
+        container.add(&boost_widget);
+
+        let caption_label = gtk::Label::new(None);
+        caption_label.set_name("caption");
+        container.add(&caption_label);
+
         let bool_label = gtk::Label::new(None);
         bool_label.set_name("boolean");
         container.add(&bool_label);
 
+        let text_label = gtk::Label::new(None);
+        text_label.set_name("text");
+        text_label.set_line_wrap(true);
+        text_label.set_justify(Justification::Center);
+        container.add(&text_label);
+
         outer_container.show_all();
         container.get_children().iter().for_each(|w| w.hide());
 
@@ -146,12 +154,160 @@ font-size: 36pt;
 "#.as_bytes(),
             ).unwrap();
         gtk::StyleContext::add_provider_for_screen(
-            &screen,
+            &Self::screen(&gtk_window),
             &default_css,
             STYLE_PROVIDER_PRIORITY_APPLICATION,
         );
 
-        if let Some(ref path) = config.window.css {
+        let mut window = Window {
+            config,
+            timeout: None,
+            css: None,
+            #[cfg(feature = "x11_struts")]
+            struts: Struts::connect()
+                .map_err(|e| warn!("Cannot read the struts of panels: {}", e))
+                .ok(),
+            widgets: Widgets {
+                gtk_window,
+                outer_container,
+                container,
+                icon_image,
+                icon,
+                scale_adjustment,
+                scale_widget,
+                boost_adjustment,
+                boost_widget,
+                caption_label,
+                bool_label,
+                text_label,
+            },
+        };
+        window.configure();
+        window.load_css();
+        window
+    }
+
+    fn screen(gtk_window: &gtk::Window) -> gdk::Screen {
+        gtk_window
+            .get_screen()
+            .expect("Expected GTK window to have a GDK screen.")
+    }
+
+    /// The monitor to show the popup on, see `config::Placement`. Falls back
+    /// to the primary monitor if the configured one cannot be found, e.g.
+    /// when no window has the focus.
+    fn monitor(&self, screen: &gdk::Screen) -> i32 {
+        let window = &self.config.window;
+        let monitor = match window.placement {
+            Placement::Primary => None,
+            Placement::Pointer => gdk::Display::get_default()
+                .and_then(|display| display.get_default_seat())
+                .and_then(|seat| seat.get_pointer())
+                .map(|pointer| {
+                    let (_, x, y) = pointer.get_position();
+                    screen.get_monitor_at_point(x, y)
+                }),
+            // Reads `_NET_ACTIVE_WINDOW` on X11
+            Placement::Focused => screen
+                .get_active_window()
+                .map(|active| screen.get_monitor_at_window(&active)),
+            Placement::Output => window.output.as_ref().and_then(|output| {
+                (0..screen.get_n_monitors()).find(|&monitor| {
+                    screen.get_monitor_plug_name(monitor).as_ref() == Some(output)
+                })
+            }),
+        };
+        monitor.unwrap_or_else(|| screen.get_primary_monitor())
+    }
+
+    /// The work area of `monitor`: the monitor without the space reserved by
+    /// panels, as reported by the window manager (`_NET_WORKAREA` on X11) and
+    /// by the struts of the panels.
+    fn work_area(&self, screen: &gdk::Screen, monitor: i32) -> gdk::Rectangle {
+        let area = screen.get_monitor_workarea(monitor);
+        self.without_struts(area, screen.get_monitor_scale_factor(monitor))
+    }
+
+    #[cfg(feature = "x11_struts")]
+    fn without_struts(&self, area: gdk::Rectangle, scale: i32) -> gdk::Rectangle {
+        match self.struts {
+            Some(ref struts) => struts.apply(area, scale),
+            None => area,
+        }
+    }
+
+    #[cfg(not(feature = "x11_struts"))]
+    fn without_struts(&self, area: gdk::Rectangle, _scale: i32) -> gdk::Rectangle {
+        area
+    }
+
+    /// Move the window to its place on the monitor chosen by `monitor`.
+    fn place(&self) {
+        let window = &self.config.window;
+        let screen = Self::screen(&self.widgets.gtk_window);
+        let monitor = self.monitor(&screen);
+        let area = match window.area {
+            Area::Monitor => screen.get_monitor_geometry(monitor),
+            Area::WorkArea => self.work_area(&screen, monitor),
+        };
+
+        self.widgets.gtk_window.move_(
+            area.x + dimen!(
+                MarginHoriz::Left,
+                MarginHoriz::Right,
+                MarginHoriz::Center,
+                area.width - window.width,
+                window.margin_horiz
+            ),
+            area.y + dimen!(
+                MarginVert::Top,
+                MarginVert::Bottom,
+                MarginVert::Center,
+                area.height - window.height,
+                window.margin_vert
+            ),
+        );
+    }
+
+    /// Apply the geometry and presentation options of the configuration.
+    fn configure(&self) {
+        let config = &self.config;
+        let widgets = &self.widgets;
+
+        // Actually set up the window
+        widgets
+            .gtk_window
+            .resize(config.window.width, config.window.height);
+        self.place();
+
+        widgets
+            .gtk_window
+            .set_opacity(config.window.opacity.min(100) as f64 / 100.0);
+
+        widgets
+            .outer_container
+            .set_spacing(config.window.spacing as i32);
+        widgets
+            .outer_container
+            .set_border_width(config.window.spacing);
+        widgets.container.set_spacing(config.window.spacing as i32);
+
+        widgets
+            .scale_widget
+            .set_draw_value(config.percentage.show_numeric);
+        widgets
+            .boost_widget
+            .set_draw_value(config.percentage.show_numeric);
+    }
+
+    /// Load the custom CSS file, replacing the one loaded before.
+    fn load_css(&mut self) {
+        let screen = Self::screen(&self.widgets.gtk_window);
+        if let Some(css_provider) = self.css.take() {
+            gtk::StyleContext::remove_provider_for_screen(&screen, &css_provider);
+        }
+
+        if let Some(ref path) = self.config.window.css {
             match path.as_path().to_str() {
                 Some(path) => {
                     let css_provider = gtk::CssProvider::new();
@@ -163,50 +319,180 @@ font-size: 36pt;
                             &css_provider,
                             STYLE_PROVIDER_PRIORITY_APPLICATION,
                         );
+                        self.css = Some(css_provider);
                     }
                 }
                 None => error!("Custom CSS path `{:#?}` is not valid unicode", path),
             }
         }
+    }
 
-        Window {
-            config,
-            timeout: None,
-            widgets: Widgets {
-                gtk_window,
-                outer_container,
-                container,
-                icon,
-                scale_adjustment,
-                scale_widget,
-                bool_label,
+    /// Switch to a reloaded configuration.
+    fn reload(&mut self, config: Config, actor: Actor<Self>) {
+        modules::resubscribe(&self.config, &config, actor.clone());
+        if config.files() != self.config.files() {
+            config::watch(&config, actor);
+        }
+
+        self.config = config;
+        self.configure();
+        self.load_css();
+        info!("Reloaded the configuration");
+    }
+
+    /// Hide the widgets of the previous message.
+    fn clear(&self) {
+        self.widgets
+            .container
+            .get_children()
+            .iter()
+            .for_each(|w| w.hide());
+    }
+
+    /// Set the CSS class of the scales for `value` as configured in
+    /// `[percentage.thresholds]`, so that themes can style e.g. low batteries
+    /// or boosted volumes.
+    fn set_level(&self, value: f64) {
+        let class = self.config.percentage.thresholds.class(value);
+        for scale in &[&self.widgets.scale_widget, &self.widgets.boost_widget] {
+            if let Some(context) = scale.get_style_context() {
+                for &level in &["critical", "low", "high"] {
+                    if Some(level) == class {
+                        context.add_class(level);
+                    } else {
+                        context.remove_class(level);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Where to take `icon` of a message from: the name of an icon chosen by
+    /// a module (see `icons::Icon`), the name of an icon in the theme, the
+    /// absolute path of an image file, or any other text.
+    fn icon_source<'a>(&'a self, icon: &'a str) -> IconSource<'a> {
+        let theme = gtk::IconTheme::get_default();
+        let themed = |name: &str| theme.as_ref().map_or(false, |theme| theme.has_icon(name));
+
+        match Icon::from_name(icon) {
+            Some(semantic) => match self.config.icons.get(semantic.name()) {
+                Some(&IconOverride {
+                    glyph: Some(ref glyph),
+                    ..
+                }) => IconSource::Text(glyph),
+                Some(&IconOverride {
+                    image: Some(ref path),
+                    ..
+                }) => IconSource::File(path),
+                _ => {
+                    let names = semantic.theme_names();
+                    // Let GTK show its placeholder if the theme has none of them
+                    let name = names.iter().find(|&&name| themed(name));
+                    IconSource::Theme(name.unwrap_or(&names[0]))
+                }
             },
+            None if themed(icon) => IconSource::Theme(icon),
+            None if Path::new(icon).is_absolute() && Path::new(icon).is_file() => {
+                IconSource::File(Path::new(icon))
+            }
+            None => IconSource::Text(icon),
         }
     }
 
+    /// Show `icon` in the widget for its source, hiding the other one.
+    fn set_icon(&self, icon: &str) {
+        let widgets = &self.widgets;
+        let size = self.config.window.icon_size;
+
+        let text = match self.icon_source(icon) {
+            IconSource::Theme(name) => {
+                widgets
+                    .icon_image
+                    .set_from_icon_name(name, IconSize::Dialog.into());
+                widgets.icon_image.set_pixel_size(size);
+                None
+            }
+            IconSource::File(path) => match Pixbuf::new_from_file_at_size(path, size, size) {
+                Ok(pixbuf) => {
+                    widgets.icon_image.set_from_pixbuf(&pixbuf);
+                    None
+                }
+                Err(e) => {
+                    error!("Failed to load icon from {:?}: {}", path, e);
+                    Some("")
+                }
+            },
+            IconSource::Text(text) => Some(text),
+        };
+
+        widgets.icon_image.set_visible(text.is_none());
+        widgets.icon.set_text(text.unwrap_or(""));
+        widgets.icon.set_visible(!text.unwrap_or("").is_empty());
+    }
+
+    /// Show the window with `icon`, which is hidden if empty, until the
+    /// timeout.
+    fn present(&mut self, icon: &str, actor: Actor<Self>) {
+        self.set_icon(icon);
+        self.place();
+        self.widgets.gtk_window.show();
+
+        self.hide_timeout(actor);
+    }
+
     /// Hide the window after `config.window.duration` milliseconds.
     fn hide_timeout(&mut self, actor: Actor<Self>) {
-        if let Some(ref id) = self.timeout {
-            // Hacky, somewhat unsafe (owner of SourceId may not expect
-            // it to invalidate, but in this specific situation it
-            // should be fine as we set self.timeout to none in the
-            // `Hide` branch) clone impl for glib::SourceId
-            let id: u32 = id.to_glib();
-            let id: SourceId = SourceId::from_glib(id);
-            source_remove(id);
-        }
+        self.cancel_timeout();
         self.timeout = Some(gtk::timeout_add(self.config.window.duration, move || {
+            // Lets modules tell that what they showed has expired
+            subscribable::publish("ui", &Hide);
             actor.tell(Hide).unwrap();
-            Continue(false)
+            // `Hide` is handled later, and removes the source then. Removing
+            // it here as well would remove it twice.
+            Continue(true)
         }));
     }
+
+    /// Remove the pending timeout that hides the window, if any.
+    fn cancel_timeout(&mut self) {
+        if let Some(id) = self.timeout.take() {
+            source_remove(id);
+        }
+    }
 }
 
-#[derive(Clone, Debug)]
+enum IconSource<'a> {
+    /// Name of an icon in the icon theme
+    Theme(&'a str),
+    /// Image file
+    File(&'a Path),
+    /// Text shown in place of an image, empty for no icon
+    Text(&'a str),
+}
+
+/// Text of a message, which modules mostly know at compile time
+pub type Text = Cow<'static, str>;
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Msg {
-    ShowPercent(&'static str, f64),
-    ShowBool(&'static str, &'static str),
+    /// Icon and value from 0.0 to 1.0. Icons are described at
+    /// `Window::icon_source`.
+    ShowPercent(Text, f64),
+    /// Like `ShowPercent`, with a caption below the scale
+    ShowCaptionedPercent(Text, f64, Text),
+    /// Like `ShowPercent` for values that may exceed 1.0, up to the maximum
+    /// given last, e.g. 1.5 for volumes boosted to 150%
+    ShowBoostedPercent(Text, f64, f64),
+    /// Icon and a label, shown if `boolean.show_label` is set
+    ShowBool(Text, Text),
+    /// Icon, which may be empty, and text that is always shown, e.g. for
+    /// notifications
+    ShowText(Text, Text),
     Hide,
+    /// Read the configuration again, and apply it if it is valid.
+    Reload,
+    /// Monitors were added, removed or re-arranged.
+    MonitorsChanged,
     Quit,
 }
 
@@ -222,71 +508,75 @@ impl Model for Window {
             }
         });
 
-        // Subscribe to modules
-        macro_rules! subscribe {
-            ($module:ident, $params:expr) => {
-                $crate::$module::Subscription::subscribe(
-                    actor.clone(),
-                    stringify!($module),
-                    $params,
-                );
-            };
-        }
-
-        #[cfg(feature = "x11_backlight")]
-        {
-            let window = self
-                .widgets
-                .gtk_window
-                .get_window()
-                .expect("Expected GTK Window to have a GDK window")
-                .to_glib_none()
-                .0;
-            let window = unsafe { ::x11_backlight::gdk_x11_window_get_xid(window) };
-            subscribe!(x11_backlight, window);
-        }
+        Self::screen(&self.widgets.gtk_window).connect_monitors_changed({
+            let actor = actor.clone();
+            move |_| {
+                actor.tell(MonitorsChanged).unwrap();
+            }
+        });
 
-        #[cfg(feature = "alsa_volume")]
-        subscribe!(alsa_volume, ());
+        // Subscribe to modules enabled in the configuration
+        modules::subscribe_enabled(&self.config, actor.clone());
+        config::watch(&self.config, actor);
     }
 
     fn update(&mut self, msg: Self::Message, actor: Actor<Self>) {
         match msg {
             ShowPercent(icon, value) => {
-                self.widgets
-                    .container
-                    .get_children()
-                    .iter()
-                    .for_each(|w| w.hide());
-
-                self.widgets.icon.set_text(icon);
+                self.clear();
+                self.set_level(value);
                 self.widgets.scale_adjustment.set_value(value * 100.0);
-
                 self.widgets.scale_widget.show();
-                self.widgets.gtk_window.show();
-
-                self.hide_timeout(actor);
+                self.present(&icon, actor);
+            }
+            ShowCaptionedPercent(icon, value, caption) => {
+                self.clear();
+                self.set_level(value);
+                self.widgets.scale_adjustment.set_value(value * 100.0);
+                self.widgets.caption_label.set_text(&caption);
+                self.widgets.scale_widget.show();
+                self.widgets.caption_label.show();
+                self.present(&icon, actor);
+            }
+            ShowBoostedPercent(icon, value, max) => {
+                self.clear();
+                self.set_level(value);
+                // The upper bound includes the page size, as for the scale
+                let max = max.max(value).max(1.0);
+                self.widgets.boost_adjustment.set_upper(max * 100.0 + 1.0);
+                self.widgets.boost_adjustment.set_value(value * 100.0);
+                self.widgets.boost_widget.show();
+                self.present(&icon, actor);
             }
             ShowBool(icon, label) => {
-                self.widgets
-                    .container
-                    .get_children()
-                    .iter()
-                    .for_each(|w| w.hide());
-
-                self.widgets.icon.set_text(icon);
+                self.clear();
                 if self.config.boolean.show_label {
-                    self.widgets.bool_label.set_text(label);
+                    self.widgets.bool_label.set_text(&label);
                     self.widgets.bool_label.show();
                 }
-                self.widgets.gtk_window.show();
-
-                self.hide_timeout(actor);
+                self.present(&icon, actor);
+            }
+            ShowText(icon, text) => {
+                self.clear();
+                self.widgets.text_label.set_text(&text);
+                self.widgets.text_label.show();
+                self.present(&icon, actor);
             }
             Hide => {
-                self.timeout = None;
+                // Otherwise the timeout of this popup hides the next one early
+                self.cancel_timeout();
                 self.widgets.gtk_window.hide();
             }
+            Reload => match config::reload() {
+                Ok(config) => self.reload(config, actor),
+                Err(msg) => error!("Keeping the current configuration:\n  {}", msg),
+            },
+            MonitorsChanged => {
+                // Otherwise placed when shown next
+                if self.widgets.gtk_window.is_visible() {
+                    self.place();
+                }
+            }
             Quit => {
                 gtk::main_quit();
             }
@@ -0,0 +1,410 @@
+// This file is part of perspektiv, a userspace daemon for graphically reporting
+// system events.
+// Copyright © 2018  Henrik Laxhuber <henrik@laxhuber.com>
+//
+// perspektiv is free software: you can redistribute it and/or modify it under
+// the terms of the GNU General Public License, version 3, as published by the
+// Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A
+// PARTICULAR PURPOSE. See the GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License along with
+// this program. If not, see <https://www.gnu.org/licenses/>.
+
+// Problems found in the configuration file, collected into a report instead of
+// stopping at the first one. Every problem names the key it is about, which is
+// also used to find its position in the file.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde::de::{self, Deserialize, DeserializeOwned, Deserializer, Visitor};
+use toml;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+pub struct Problem {
+    pub severity: Severity,
+    /// Dotted path of the key, e.g. `window.opacity`. Empty for the file as a
+    /// whole.
+    pub key: String,
+    pub message: String,
+    pub suggestion: String,
+    /// Line and column, starting at 1
+    pub position: Option<(usize, usize)>,
+}
+
+impl Problem {
+    pub fn error<K: Into<String>, M: Into<String>>(key: K, message: M) -> Self {
+        Problem {
+            severity: Severity::Error,
+            key: key.into(),
+            message: message.into(),
+            suggestion: "See the default configuration for valid settings.".to_string(),
+            position: None,
+        }
+    }
+
+    pub fn warning<K: Into<String>, M: Into<String>>(key: K, message: M) -> Self {
+        Problem {
+            severity: Severity::Warning,
+            ..Problem::error(key, message)
+        }
+    }
+
+    pub fn suggest<S: Into<String>>(mut self, suggestion: S) -> Self {
+        self.suggestion = suggestion.into();
+        self
+    }
+
+    /// Convert an error of the TOML parser. Keys of errors found while parsing
+    /// a table are relative to `key`.
+    pub fn from_toml(key: &str, e: &toml::de::Error) -> Self {
+        // The key path is only exposed through the message
+        let message = e.to_string();
+        let message = match message.rfind(" at line ") {
+            Some(i) if e.line_col().is_some() => message[..i].to_string(),
+            _ => message,
+        };
+        let (message, relative) = match message.rfind(" for key `") {
+            Some(i) => (
+                message[..i].to_string(),
+                Some(message[i + 10..].trim_end_matches('`').to_string()),
+            ),
+            None => (message, None),
+        };
+        let key = match relative {
+            Some(ref relative) if key.is_empty() => relative.clone(),
+            Some(ref relative) => format!("{}.{}", key, relative),
+            None => key.to_string(),
+        };
+
+        let suggestion = if e.line_col().is_some() {
+            "Fix the TOML syntax, see https://toml.io."
+        } else if message.starts_with("missing field") {
+            "Add the missing key; the default configuration lists all keys with their meaning."
+        } else if message.starts_with("unknown variant") {
+            "Use one of the expected values."
+        } else if message.starts_with("invalid type") || message.starts_with("invalid value") {
+            "Change the value to one of the expected type."
+        } else {
+            "See the default configuration for valid settings."
+        };
+        Problem {
+            position: e.line_col().map(|(line, col)| (line + 1, col + 1)),
+            ..Problem::error(key, message).suggest(suggestion)
+        }
+    }
+}
+
+/// All problems found in a configuration file.
+pub struct Report {
+    pub file: PathBuf,
+    source: String,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    pub fn new(file: &Path, source: &str) -> Self {
+        Report {
+            file: file.to_path_buf(),
+            source: source.to_string(),
+            problems: Vec::new(),
+        }
+    }
+
+    /// Add a problem, finding its position by its key if it has none.
+    pub fn push(&mut self, mut problem: Problem) {
+        if problem.position.is_none() {
+            problem.position = locate(&self.source, &problem.key);
+        }
+        self.problems.push(problem);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.problems
+            .iter()
+            .any(|problem| problem.severity == Severity::Error)
+    }
+
+    /// Describe a problem, with the file and position it was found at.
+    pub fn describe(&self, problem: &Problem) -> String {
+        let position = match problem.position {
+            Some((line, col)) => format!("{}:{}:{}", self.file.display(), line, col),
+            None => self.file.display().to_string(),
+        };
+        let key = if problem.key.is_empty() {
+            String::new()
+        } else {
+            format!("`{}`: ", problem.key)
+        };
+        format!(
+            "{}: {}{}\n  help: {}",
+            position, key, problem.message, problem.suggestion
+        )
+    }
+
+    /// Log every problem as an error or a warning.
+    pub fn log(&self) {
+        for problem in &self.problems {
+            match problem.severity {
+                Severity::Error => error!("{}", self.describe(problem)),
+                Severity::Warning => warn!("{}", self.describe(problem)),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for problem in &self.problems {
+            let severity = match problem.severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            };
+            writeln!(f, "{}: {}", severity, self.describe(problem))?;
+        }
+        Ok(())
+    }
+}
+
+/// Find the line and column of `key` in a TOML document: the line defining
+/// it, the header of the table it names, or the line defining an inline table
+/// it is part of.
+fn locate(source: &str, key: &str) -> Option<(usize, usize)> {
+    if key.is_empty() {
+        return None;
+    }
+    let path: Vec<&str> = key.split('.').collect();
+    let mut table: Vec<String> = Vec::new();
+    let mut enclosing = None;
+
+    for (i, line) in source.lines().enumerate() {
+        let trimmed = line.trim_start();
+        let position = Some((i + 1, line.len() - trimmed.len() + 1));
+        if trimmed.starts_with('#') {
+            continue;
+        }
+
+        if trimmed.starts_with('[') {
+            let header = trimmed.trim_start_matches('[').split(']').next().unwrap_or("");
+            table = header
+                .split('.')
+                .map(|part| part.trim().trim_matches('"').to_string())
+                .collect();
+            if table == path {
+                return position;
+            }
+            if path.starts_with(&table.iter().map(|s| s.as_str()).collect::<Vec<_>>()) {
+                enclosing = position;
+            }
+        } else if let Some(eq) = trimmed.find('=') {
+            let full: Vec<&str> = table
+                .iter()
+                .map(|s| s.as_str())
+                .chain(trimmed[..eq].split('.').map(|part| part.trim().trim_matches('"')))
+                .collect();
+            if full == path {
+                return position;
+            }
+            if path.starts_with(&full) {
+                enclosing = position;
+            }
+        }
+    }
+    enclosing
+}
+
+/// Deserializer that records the fields of a struct instead of deserializing
+/// it, see `fields`.
+struct Probe(&'static [&'static str]);
+
+impl<'de, 'a> Deserializer<'de> for &'a mut Probe {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0 = fields;
+        Err(de::Error::custom("probed"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct
+        map enum identifier ignored_any
+    }
+}
+
+/// Keys known to the configuration type `T`, as generated by
+/// `#[derive(Deserialize)]`. Empty for types that are not structs, or that
+/// have flattened fields.
+pub fn fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+    let mut probe = Probe(&[]);
+    let _ = T::deserialize(&mut probe);
+    probe.0
+}
+
+/// Number of single character edits turning `a` into `b`
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let substitution = previous + if ca == b[j] { 0 } else { 1 };
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// A warning about the key `name` in the table `table`, which only knows the
+/// keys `known`.
+pub fn unknown_key(table: &str, name: &str, known: &[&str]) -> Problem {
+    let key = if table.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", table, name)
+    };
+    let closest = known
+        .iter()
+        .map(|k| (distance(name, k), k))
+        .min()
+        .filter(|&(d, _)| d <= 2);
+    let suggestion = match closest {
+        Some((_, k)) => format!("Did you mean `{}`?", k),
+        None => format!(
+            "Remove it; known keys are {}.",
+            known
+                .iter()
+                .map(|k| format!("`{}`", k))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    Problem::warning(key, "unknown key, ignoring it").suggest(suggestion)
+}
+
+/// Warn about keys in the table `value` that `T` does not know.
+pub fn unknown_keys<'de, T: Deserialize<'de>>(report: &mut Report, key: &str, value: &toml::Value) {
+    let known = fields::<T>();
+    if known.is_empty() {
+        return;
+    }
+    if let Some(table) = value.as_table() {
+        for name in table.keys().filter(|name| !known.contains(&name.as_str())) {
+            report.push(unknown_key(key, name, known));
+        }
+    }
+}
+
+/// Deserialize the table at `key`, reporting unknown keys and errors.
+pub fn table<T: DeserializeOwned>(report: &mut Report, key: &str, value: &toml::Value) -> Option<T> {
+    unknown_keys::<T>(report, key, value);
+    match value.clone().try_into() {
+        Ok(value) => Some(value),
+        Err(e) => {
+            report.push(Problem::from_toml(key, &e));
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Window {
+        width: i32,
+        duration: u32,
+    }
+
+    const SOURCE: &str = "# Comment\n\n[window]\n  width = 300\n  durration = 1200\n";
+
+    fn report(source: &str) -> Report {
+        Report::new(Path::new("config.toml"), source)
+    }
+
+    #[test]
+    fn suggests_closest_key() {
+        let mut report = report(SOURCE);
+        let value: toml::Value = toml::from_str(SOURCE).unwrap();
+        unknown_keys::<Window>(&mut report, "window", &value["window"]);
+
+        assert_eq!(report.problems.len(), 1);
+        let problem = &report.problems[0];
+        assert!(problem.severity == Severity::Warning);
+        assert_eq!(problem.key, "window.durration");
+        assert_eq!(problem.suggestion, "Did you mean `duration`?");
+        assert!(!report.has_errors());
+    }
+
+    #[test]
+    fn lists_known_keys_without_close_match() {
+        let problem = unknown_key("window", "colour", &["width", "duration"]);
+        assert_eq!(
+            problem.suggestion,
+            "Remove it; known keys are `width`, `duration`."
+        );
+    }
+
+    #[test]
+    fn reports_line_and_column() {
+        let mut report = report(SOURCE);
+        report.push(Problem::warning(
+            "window.durration",
+            "unknown key, ignoring it",
+        ));
+        report.push(Problem::error("window", "is broken"));
+        report.push(Problem::error("window.height", "is missing"));
+
+        assert_eq!(report.problems[0].position, Some((5, 3)));
+        assert_eq!(report.problems[1].position, Some((3, 1)));
+        // Keys that are not in the file are reported at their table
+        assert_eq!(report.problems[2].position, Some((3, 1)));
+        assert_eq!(
+            report.describe(&report.problems[1]),
+            "config.toml:3:1: `window`: is broken\n  \
+             help: See the default configuration for valid settings."
+        );
+    }
+
+    #[test]
+    fn reports_wrong_value_type() {
+        let source = "[window]\nwidth = 300\nduration = \"fast\"\n";
+        let mut report = report(source);
+        let value: toml::Value = toml::from_str(source).unwrap();
+        let window = table::<Window>(&mut report, "window", &value["window"]);
+
+        assert!(window.is_none());
+        assert!(report.has_errors());
+        let problem = &report.problems[0];
+        assert_eq!(problem.key, "window.duration");
+        assert!(problem.message.starts_with("invalid type"));
+        assert_eq!(
+            problem.suggestion,
+            "Change the value to one of the expected type."
+        );
+        assert_eq!(problem.position, Some((3, 1)));
+    }
+}
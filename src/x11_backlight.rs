@@ -13,214 +13,577 @@
 // You should have received a copy of the GNU General Public License along with
 // this program. If not, see <https://www.gnu.org/licenses/>.
 
-// BIG TODO:
-// Switch to xcb-rs
-extern crate x11;
+// Backlight drivers in X expose the brightness as the RandR output property
+// `Backlight` (or `BACKLIGHT` for older drivers), whose valid values are a
+// range. Changes of output properties are reported as RandR notify events,
+// which carry the output and property but not the new value.
+//
+// Which of the two names a driver uses is a property of the output, not the
+// display: any client may intern either atom, so the existence of an atom says
+// nothing about which outputs have such a property. Hence both names are
+// probed on every output.
 
-use std::{ffi::CString, mem::uninitialized, ops::Range, os::raw::*, ptr};
+extern crate xcb;
 
-use gdk_sys;
+use std::{
+    fmt::Write,
+    ops::Range,
+    os::unix::io::{AsRawFd, RawFd},
+    ptr,
+};
 
-use self::x11::xlib; // also allow scoped access for disambiguation
-use self::x11::{xlib::*, xrandr::*};
+use self::xcb::randr;
 
+use icons::Icon;
+use reactor::Reactive;
 use subscribable;
-use subscribable::{PollFn, Subscribable};
 use ui;
 
+/// Configuration section `[modules.x11_backlight]`
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// X display to connect to, e.g. `:0`. If unset, `$DISPLAY` is used.
+    pub display: Option<String>,
+    /// Names of the RandR outputs to watch (e.g. `eDP-1`). If empty, all
+    /// outputs with a backlight are watched.
+    pub outputs: Vec<String>,
+}
+
+/// Names of the backlight property, in order of preference
+const PROPERTY_NAMES: [&str; 2] = ["Backlight", "BACKLIGHT"];
+
+/// A backlight property of an output
+#[derive(Clone)]
+struct Property {
+    name: &'static str,
+    atom: xcb::Atom,
+    range: Range<i32>,
+}
+
+/// A RandR output with a backlight property
+struct Output {
+    id: randr::Output,
+    name: String,
+    property: Property,
+}
+
+/// A connection to the X server with RandR, and the outputs of its default
+/// screen.
+struct Display {
+    connection: xcb::Connection,
+    root: xcb::Window,
+    /// Type of the first RandR event, to tell RandR events apart from others
+    event_base: u8,
+    /// Atoms of `PROPERTY_NAMES` that exist on the display
+    atoms: Vec<(&'static str, xcb::Atom)>,
+}
+
+impl Display {
+    fn open(config: &Config) -> Result<Self, String> {
+        let display = config.display.as_ref().map(|d| d.as_str());
+        let (connection, screen) = xcb::Connection::connect(display).map_err(|e| {
+            format!(
+                "Cannot open display {} ({})",
+                display.unwrap_or("from $DISPLAY"),
+                e
+            )
+        })?;
+
+        let event_base = match connection.get_extension_data(randr::id()) {
+            Some(ref randr) if randr.present() => randr.first_event(),
+            _ => return Err("RandR extension missing".to_string()),
+        };
+        let version = randr::query_version(&connection, 1, 3)
+            .get_reply()
+            .map_err(|e| format!("Cannot query RandR version (error {})", e.error_code()))?;
+        err_expect!(
+            version.major_version() > 1
+                || (version.major_version() == 1 && version.minor_version() > 2),
+            "RandR version too old"
+        );
+
+        let root = connection
+            .get_setup()
+            .roots()
+            .nth(screen as usize)
+            .map(|screen| screen.root())
+            .ok_or("Cannot get root window of the default screen")?;
+
+        // An atom that does not exist cannot name a property of any output
+        let mut atoms = Vec::new();
+        for &name in &PROPERTY_NAMES {
+            match intern_atom(&connection, name)? {
+                0 => {}
+                atom => atoms.push((name, atom)),
+            }
+        }
+
+        Ok(Display {
+            connection,
+            root,
+            event_base,
+            atoms,
+        })
+    }
+
+    /// All outputs of the default screen, by id and name
+    fn outputs(&self) -> Result<Vec<(randr::Output, String)>, String> {
+        let resources = randr::get_screen_resources_current(&self.connection, self.root)
+            .get_reply()
+            .map_err(|e| format!("Cannot get RandR resources (error {})", e.error_code()))?;
+        Ok(resources
+            .outputs()
+            .iter()
+            .filter_map(|&id| {
+                let info =
+                    randr::get_output_info(&self.connection, id, resources.config_timestamp())
+                        .get_reply()
+                        .ok()?;
+                Some((id, String::from_utf8_lossy(info.name()).into_owned()))
+            }).collect())
+    }
+
+    /// The backlight properties that `output` has, in order of preference.
+    fn probe(&self, output: randr::Output) -> Vec<Property> {
+        self.atoms
+            .iter()
+            .filter_map(|&(name, atom)| {
+                // Fails with BadName if the output has no such property
+                let reply = randr::query_output_property(&self.connection, output, atom)
+                    .get_reply()
+                    .ok()?;
+                let values = reply.valid_values();
+                if !reply.range() || values.len() != 2 || values[0] == values[1] {
+                    return None;
+                }
+                Some(Property {
+                    name,
+                    atom,
+                    range: values[0]..values[1],
+                })
+            }).collect()
+    }
+
+    fn get_brightness(&self, output: randr::Output, property: &Property) -> Result<f64, String> {
+        let reply = randr::get_output_property(
+            &self.connection,
+            output,
+            property.atom,
+            xcb::ATOM_INTEGER,
+            0,     // offset
+            4,     // length
+            false, // delete
+            false, // pending
+        ).get_reply()
+        .map_err(|e| format!("Cannot get backlight property (error {})", e.error_code()))?;
+
+        err_expect!(
+            reply.type_() == xcb::ATOM_INTEGER,
+            "X11 did not return an integer for the backlight property"
+        );
+        err_expect!(
+            reply.num_items() == 1,
+            "Got zero or multiple values for backlight property; expected exactly one"
+        );
+        err_expect!(
+            reply.format() == 32 && reply.data().len() >= 4,
+            "Backlight was not a 32-bit value as expected"
+        );
+
+        // The data of the reply need not be aligned
+        let brightness = unsafe { ptr::read_unaligned(reply.data().as_ptr() as *const i32) };
+        let range = &property.range;
+        Ok((brightness - range.start) as f64 / (range.end - range.start) as f64)
+    }
+}
+
 pub struct Backlight {
-    display: *mut Display,
-    backlight: Atom,
-    output: RROutput,
-    backlight_range: Range<c_long>,
+    display: Display,
+    outputs: Vec<Output>,
 }
 
 impl Backlight {
     /// Initialise the module by connecting to the X11 server and getting handles
-    /// for the display and backlight.
-    fn new() -> Result<Backlight, String> {
-        unsafe {
-            let display = XOpenDisplay(ptr::null());
-            err_if!(
-                display.is_null(),
-                "Cannot open default display (maybe no $DISPLAY environment variable set)"
-            );
+    /// for the outputs with a backlight. Also subscribes to changes of output
+    /// properties.
+    fn new(config: &Config) -> Result<Backlight, String> {
+        let display = Display::open(config)?;
+        err_if!(
+            display.atoms.is_empty(),
+            "Given display has no property `Backlight` or `BACKLIGHT`"
+        );
 
-            let mut major: c_int = uninitialized();
-            let mut minor: c_int = uninitialized();
-            err_expect!(
-                XRRQueryVersion(display, &mut major as *mut c_int, &mut minor as *mut c_int) != 0,
-                "RandR extension missing"
-            );
-            err_expect!(
-                major > 1 || (major == 1 && minor > 2),
-                "RandR version too old"
-            );
+        let outputs: Vec<Output> = display
+            .outputs()?
+            .into_iter()
+            .filter(|&(_, ref name)| config.outputs.is_empty() || config.outputs.contains(name))
+            .filter_map(|(id, name)| {
+                // Outputs without a backlight have no such property
+                let property = display.probe(id).into_iter().next()?;
+                Some(Output { id, name, property })
+            }).collect();
 
-            // Get atom (numeric ID) for the Backlight property
-            let mut backlight_name = CString::new("Backlight").unwrap().into_raw();
-            let mut backlight = XInternAtom(display, backlight_name, true as i32);
-            if backlight != 0 {
-                backlight_name = CString::new("BACKLIGHT").unwrap().into_raw();
-                backlight = XInternAtom(display, backlight_name, true as i32);
-                err_if!(
-                    backlight == 0,
-                    "Given display has no property `Backlight` or `BACKLIGHT`"
-                );
+        err_if!(
+            outputs.is_empty(),
+            if config.outputs.is_empty() {
+                "Cannot find any RandR outputs with a backlight".to_string()
+            } else {
+                format!(
+                    "Cannot find RandR outputs `{}` with a backlight",
+                    config.outputs.join("`, `")
+                )
             }
-            let _ = CString::from_raw(backlight_name); // back into Rust memory management to free properly
-
-            let root = XDefaultRootWindow(display);
-            err_if!(
-                root == 0,
-                "Cannot get default root window for given display"
+        );
+        for output in &outputs {
+            info!(
+                "Watching backlight of RandR output {} through property `{}`",
+                output.name, output.property.name
             );
+        }
 
-            let resources = XRRGetScreenResources(display, root);
-            err_if!(
-                resources.is_null(),
-                "Cannot get xrandr resources for given display and root"
-            );
+        // Output property changes are reported to the root window
+        randr::select_input(
+            &display.connection,
+            display.root,
+            randr::NOTIFY_MASK_OUTPUT_PROPERTY as u16,
+        );
+        display.connection.flush();
 
-            let output_ptr = (*resources).outputs;
-            err_if!(
-                output_ptr.is_null(),
-                "Cannot get outputs for given xrandr resources"
-            );
-            let output = *output_ptr;
+        Ok(Backlight { display, outputs })
+    }
 
-            let backlight_info = XRRQueryOutputProperty(display, output, backlight);
-            err_if!(
-                backlight_info.is_null(),
-                "Cannot get property `Backlight` for given display and xrandr outputs"
-            );
+    /// Handle the pending events without blocking, and return the watched
+    /// outputs whose backlight changed.
+    fn changed(&self) -> Result<Vec<&Output>, subscribable::Error> {
+        let mut changed: Vec<&Output> = Vec::new();
+        while let Some(event) = self.display.connection.poll_for_event() {
+            // The most significant bit is set for events sent by clients
+            if event.response_type() & !0x80 != self.display.event_base + randr::NOTIFY {
+                continue;
+            }
 
-            let backlight_range = Range {
-                start: *(*backlight_info).values,
-                end: *(*backlight_info).values.offset(1),
-            };
+            let event: &randr::NotifyEvent = unsafe { xcb::cast_event(&event) };
+            if event.sub_code() != randr::NOTIFY_OUTPUT_PROPERTY as u8 {
+                continue;
+            }
+            let property = event.u().op();
+            if let Some(output) = self
+                .outputs
+                .iter()
+                .find(|o| o.id == property.output() && o.property.atom == property.atom())
+            {
+                if !changed.iter().any(|o| o.id == output.id) {
+                    changed.push(output);
+                }
+            }
+        }
+        self.display
+            .connection
+            .has_error()
+            .map_err(|_| subscribable::Error::new("Lost connection to the X server", true))?;
+        Ok(changed)
+    }
+}
 
-            XFree(backlight_info as *mut c_void);
-            XRRFreeScreenResources(resources as *mut XRRScreenResources);
+fn intern_atom(connection: &xcb::Connection, name: &str) -> Result<xcb::Atom, String> {
+    xcb::intern_atom(connection, true, name)
+        .get_reply()
+        .map(|reply| reply.atom())
+        .map_err(|e| format!("Cannot get atom `{}` (error {})", name, e.error_code()))
+}
 
-            Ok(Backlight {
-                display,
-                backlight,
-                output,
-                backlight_range,
-            })
-        }
+pub struct Subscription(Backlight);
+impl Reactive for Subscription {
+    type Params = Config;
+
+    fn start(config: Self::Params) -> Result<Self, String> {
+        Ok(Subscription(Backlight::new(&config)?))
     }
 
-    fn get_brightness(&self) -> Result<f64, String> {
-        unsafe {
-            let mut actual_type: Atom = uninitialized();
-            let mut actual_format: c_int = uninitialized();
-            let mut n_items: c_ulong = uninitialized();
-            let mut bytes_after: c_ulong = uninitialized();
-            let mut prop: *mut c_uchar = uninitialized();
-
-            XRRGetOutputProperty(
-                self.display,                     // dpy: *mut Display,
-                self.output,                      // output: RROutput,
-                self.backlight,                   // property: Atom,
-                0,                                // offset: c_long,
-                4,                                // length: c_long,
-                false as i32,                     // _delete: Bool,
-                false as i32,                     // pending: Bool,
-                0,                                // req_type: Atom,
-                &mut actual_type as *mut Atom,    // actual_type: *mut Atom,
-                &mut actual_format as *mut c_int, // actual_format: *mut c_int,
-                &mut n_items as *mut c_ulong,     // nitems: *mut c_ulong,
-                &mut bytes_after as *mut c_ulong, // bytes_after: *mut c_ulong,
-                &mut prop as *mut *mut c_uchar,   // prop: *mut *mut c_uchar
-            );
+    fn fds(&self) -> Vec<RawFd> {
+        vec![self.0.display.connection.as_raw_fd()]
+    }
 
-            err_expect!(
-                actual_type == XA_INTEGER,
-                "X11 did not return an integer for the backlight property"
-            );
-            err_expect!(
-                n_items == 1,
-                "Got zero or multiple values for backlight property; expected exactly one"
-            );
-            err_expect!(
-                actual_format == 32,
-                "Backlight was not a 32-bit value as expected"
-            );
+    fn ready(&mut self, msgs: &mut Vec<ui::Msg>) -> Result<(), subscribable::Error> {
+        let backlight = &self.0;
+        // Events that arrive while waiting for the replies below are queued by
+        // XCB without making the descriptor readable again, so they are
+        // handled until there are none left.
+        loop {
+            let changed = backlight.changed()?;
+            if changed.is_empty() {
+                return Ok(());
+            }
+            for output in changed {
+                // The event doesn't contain the new value, so we need to query it
+                let brightness = backlight
+                    .display
+                    .get_brightness(output.id, &output.property)?;
+                let icon = Icon::brightness(brightness).into();
+                // Only name the output if it could be any of several
+                msgs.push(if backlight.outputs.len() > 1 {
+                    ui::ShowCaptionedPercent(icon, brightness, output.name.clone().into())
+                } else {
+                    ui::ShowPercent(icon, brightness)
+                });
+            }
+        }
+    }
+}
+
+/// Describe the backlight properties of all outputs, for `--diagnose`.
+pub fn diagnose(config: &Config) -> Result<String, String> {
+    let display = Display::open(config)?;
+    let mut report = String::new();
 
-            let brightness = *(prop as *const c_long);
-            XFree(prop as *mut c_void);
+    for &name in &PROPERTY_NAMES {
+        let exists = display.atoms.iter().any(|&(n, _)| n == name);
+        let _ = writeln!(
+            report,
+            "Atom `{}`: {}",
+            name,
+            if exists { "exists" } else { "does not exist" }
+        );
+    }
 
-            let brightness: f64 = ((brightness - self.backlight_range.start) as f64)
-                / ((self.backlight_range.end - self.backlight_range.start) as f64);
-            return Ok(brightness);
+    for (id, name) in display.outputs()? {
+        let watched = config.outputs.is_empty() || config.outputs.contains(&name);
+        let properties = display.probe(id);
+        let _ = writeln!(
+            report,
+            "Output {}{}:",
+            name,
+            if watched { "" } else { " (not selected in configuration)" }
+        );
+        if properties.is_empty() {
+            let _ = writeln!(report, "  no backlight property");
+        }
+        for (i, property) in properties.iter().enumerate() {
+            let value = match display.get_brightness(id, property) {
+                Ok(brightness) => format!("{:.0}%", brightness * 100.0),
+                Err(e) => format!("unreadable ({})", e.lines().next().unwrap_or("")),
+            };
+            let _ = writeln!(
+                report,
+                "  property `{}`, range {}..{}, brightness {}{}",
+                property.name,
+                property.range.start,
+                property.range.end,
+                value,
+                if i == 0 && watched { " (used)" } else { "" }
+            );
         }
     }
+    Ok(report)
 }
 
-pub struct Subscription();
-impl Subscribable for Subscription {
-    type Params = xlib::Window;
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+        time::{Duration, Instant},
+    };
 
-    fn poll_factory(window: Self::Params) -> Result<Box<PollFn>, String> {
-        let mut backlight = Backlight::new()?;
+    use libc;
 
-        // Subscribe to X11 event for (any) RandR Output Property changes on the display.
-        // Unfortunately this does not have a status return value, so who know's if it worked?
-        unsafe {
-            XRRSelectInput(backlight.display, window, RROutputPropertyNotifyMask);
+    use super::*;
+
+    /// An X server of its own for a test, which is killed when dropped
+    struct Xvfb {
+        server: Child,
+        display: String,
+        connection: xcb::Connection,
+        /// The only output of Xvfb
+        output: randr::Output,
+    }
+
+    impl Xvfb {
+        fn start() -> Xvfb {
+            // Xvfb picks a free display and writes its number to stdout once
+            // it accepts connections
+            let mut server = Command::new("Xvfb")
+                .args(["-displayfd", "1", "-nolisten", "tcp"].iter())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("Cannot start Xvfb");
+            let mut number = String::new();
+            BufReader::new(server.stdout.take().unwrap())
+                .read_line(&mut number)
+                .unwrap();
+            let display = format!(":{}", number.trim());
+
+            let (connection, _) = xcb::Connection::connect(Some(display.as_str())).unwrap();
+            let root = connection.get_setup().roots().next().unwrap().root();
+            let output = randr::get_screen_resources_current(&connection, root)
+                .get_reply()
+                .unwrap()
+                .outputs()[0];
+            Xvfb {
+                server,
+                display,
+                connection,
+                output,
+            }
         }
 
-        unsafe extern "C" fn predicate(
-            _display: *mut Display,
-            event: *mut XEvent,
-            arg: *mut c_char,
-        ) -> i32 {
-            let output = *(arg as *const RROutput); // backlight.output
-            let event = *event;
-
-            // No idea where 90 is defined, but that's what highly
-            // sophisticated println! brute-force gives me.
-            if event.type_ == 90 {
-                // is RandR event
-                // fine filtering
-                let event: XRROutputPropertyNotifyEvent = event.xrr_output_property_notify;
-                if event.subtype == RRNotify_OutputProperty && event.output == output {
-                    return true as i32;
-                }
+        fn config(&self) -> Config {
+            Config {
+                display: Some(self.display.clone()),
+                outputs: Vec::new(),
             }
+        }
 
-            return false as i32;
+        fn atom(&self, name: &str) -> xcb::Atom {
+            xcb::intern_atom(&self.connection, false, name)
+                .get_reply()
+                .unwrap()
+                .atom()
         }
 
-        let mut event: XEvent = unsafe { uninitialized() };
-        Ok(Box::new(move || {
-            loop {
-                unsafe {
-                    XIfEvent(
-                        backlight.display,
-                        &mut event as *mut XEvent,
-                        Some(predicate),
-                        &mut backlight.output as *mut _ as *mut c_char,
-                    );
-                }
-                // The event doesn't contain the new value, so we need to query it
-                match backlight.get_brightness() {
-                    Ok(brightness) => {
-                        return Ok(ui::ShowPercent("", brightness));
-                    }
-                    Err(msg) => {
-                        return Err(subscribable::Error::from(msg));
-                    }
-                }
-            }
-        }))
+        /// Give the output a backlight property `name` with a range of 0 to
+        /// 255, like a driver would.
+        fn add_backlight(&self, name: &str, value: i32) {
+            let atom = self.atom(name);
+            randr::configure_output_property_checked(
+                &self.connection,
+                self.output,
+                atom,
+                false, // pending
+                true,  // range
+                &[0, 255],
+            ).request_check()
+            .unwrap();
+            self.set_backlight(name, value);
+        }
+
+        fn set_backlight(&self, name: &str, value: i32) {
+            randr::change_output_property_checked(
+                &self.connection,
+                self.output,
+                self.atom(name),
+                xcb::ATOM_INTEGER,
+                32,
+                xcb::PROP_MODE_REPLACE as u8,
+                &[value],
+            ).request_check()
+            .unwrap();
+        }
     }
-}
 
-// Provided by gdk, but not contained in the gdk_sys crate (to the best of my
-// knowledge)
-extern "C" {
-    pub fn gdk_x11_window_get_xid(window: *const gdk_sys::GdkWindow) -> xlib::Window;
+    impl Drop for Xvfb {
+        fn drop(&mut self) {
+            let _ = self.server.kill();
+            let _ = self.server.wait();
+        }
+    }
+
+    /// Drive the module like the reactor does until it shows something.
+    fn next_msgs(module: &mut Subscription) -> Vec<ui::Msg> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut msgs = Vec::new();
+        while msgs.is_empty() && Instant::now() < deadline {
+            let mut poll_fd = libc::pollfd {
+                fd: module.fds()[0],
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            unsafe { libc::poll(&mut poll_fd, 1, 100) };
+            assert!(module.ready(&mut msgs).is_ok());
+        }
+        msgs
+    }
+
+    // Needs Xvfb; run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn shows_brightness_when_property_changes() {
+        let xvfb = Xvfb::start();
+        xvfb.add_backlight("Backlight", 128);
+        let mut module = Subscription::start(xvfb.config()).unwrap();
+
+        xvfb.set_backlight("Backlight", 51);
+        assert_eq!(
+            next_msgs(&mut module),
+            vec![ui::ShowPercent(Icon::BrightnessLow.into(), 0.2)]
+        );
+        // The end of the range is full brightness
+        xvfb.set_backlight("Backlight", 255);
+        assert_eq!(
+            next_msgs(&mut module),
+            vec![ui::ShowPercent(Icon::BrightnessHigh.into(), 1.0)]
+        );
+    }
+
+    // Needs Xvfb; run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn probes_backlight_property() {
+        let xvfb = Xvfb::start();
+        xvfb.add_backlight("Backlight", 51);
+
+        let module = Subscription::start(xvfb.config()).unwrap();
+        assert_eq!(module.0.outputs.len(), 1);
+        assert_eq!(module.0.outputs[0].property.name, "Backlight");
+        assert_eq!(module.0.outputs[0].property.range, 0..255);
+
+        let report = diagnose(&xvfb.config()).unwrap();
+        assert!(report.contains("Atom `Backlight`: exists\n"), "{}", report);
+        assert!(report.contains("Atom `BACKLIGHT`: does not exist\n"), "{}", report);
+        assert!(
+            report.contains("  property `Backlight`, range 0..255, brightness 20% (used)\n"),
+            "{}",
+            report
+        );
+    }
+
+    // Needs Xvfb; run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn probes_legacy_backlight_property() {
+        let xvfb = Xvfb::start();
+        xvfb.add_backlight("BACKLIGHT", 51);
+
+        let mut module = Subscription::start(xvfb.config()).unwrap();
+        assert_eq!(module.0.outputs.len(), 1);
+        assert_eq!(module.0.outputs[0].property.name, "BACKLIGHT");
+
+        xvfb.set_backlight("BACKLIGHT", 255);
+        assert_eq!(
+            next_msgs(&mut module),
+            vec![ui::ShowPercent(Icon::BrightnessHigh.into(), 1.0)]
+        );
+
+        let report = diagnose(&xvfb.config()).unwrap();
+        assert!(report.contains("Atom `Backlight`: does not exist\n"), "{}", report);
+        assert!(
+            report.contains("  property `BACKLIGHT`, range 0..255, brightness 100% (used)\n"),
+            "{}",
+            report
+        );
+    }
+
+    // Needs Xvfb; run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn diagnoses_output_without_backlight() {
+        let xvfb = Xvfb::start();
+        // Both atoms exist, e.g. because other clients interned them, but the
+        // output has neither property
+        xvfb.atom("Backlight");
+        xvfb.atom("BACKLIGHT");
+
+        let error = Subscription::start(xvfb.config()).err().unwrap();
+        assert!(
+            error.starts_with("Cannot find any RandR outputs with a backlight."),
+            "{}",
+            error
+        );
+
+        let report = diagnose(&xvfb.config()).unwrap();
+        assert!(report.contains("Atom `BACKLIGHT`: exists\n"), "{}", report);
+        assert!(report.contains(":\n  no backlight property\n"), "{}", report);
+    }
 }